@@ -0,0 +1,228 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use surrealdb::{engine::local::Db, sql::Thing, Datetime, Surreal};
+
+use crate::ai::EmbeddingEngine;
+
+const CACHE_TABLE: &str = "embedding_cache";
+// vectors kept at most, the oldest go first. 100k 384-dimensional vectors are about 150 MB.
+const CACHE_MAX_ENTRIES: usize = 100_000;
+// a long-running process evicts again after caching this many vectors
+const EVICT_EVERY: usize = 1_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedVector {
+    // sha256 of the model fingerprint and the text the model saw
+    id: Thing,
+    model: String,
+    vector: Vec<f32>,
+    // missing in vectors cached before eviction existed, those are evicted first
+    #[serde(default)]
+    cached_at: Option<Datetime>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+// vectors persisted by (model, text) in the local database, so re-uploading a file or asking
+// the same question again skips the forward pass
+pub struct EmbeddingCache {
+    db: Surreal<Db>,
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    // vectors stored since startup
+    stored: AtomicUsize,
+}
+
+impl EmbeddingCache {
+    pub fn new(db: Surreal<Db>, embedder: Arc<dyn EmbeddingEngine + Send + Sync>) -> Self {
+        Self {
+            db,
+            embedder,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            stored: AtomicUsize::new(0),
+        }
+    }
+
+    // lookups since startup
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    // drops vectors of other models or settings, which can no longer be hit, then the oldest
+    // ones above `CACHE_MAX_ENTRIES`
+    pub async fn evict(&self) -> Result<()> {
+        self.db
+            .query(format!("DELETE FROM {CACHE_TABLE} WHERE model != $model"))
+            .bind(("model", self.embedder.fingerprint()))
+            .await?
+            .check()
+            .context("unable to evict stale cached embeddings")?;
+
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT count() AS count FROM {CACHE_TABLE} GROUP ALL"
+            ))
+            .await?;
+        let count: Option<usize> = result.take((0, "count"))?;
+        let excess = count.unwrap_or_default().saturating_sub(CACHE_MAX_ENTRIES);
+        if excess > 0 {
+            self.db
+                .query(format!(
+                    "DELETE FROM {CACHE_TABLE} WHERE id IN \
+                     (SELECT VALUE id FROM {CACHE_TABLE} ORDER BY cached_at ASC LIMIT $excess)"
+                ))
+                .bind(("excess", excess))
+                .await?
+                .check()
+                .context("unable to evict old cached embeddings")?;
+        }
+        Ok(())
+    }
+
+    // every cached vector, of any model
+    pub async fn clear(&self) -> Result<()> {
+        self.db
+            .query(format!("DELETE FROM {CACHE_TABLE}"))
+            .await?
+            .check()
+            .context("unable to clear the embedding cache")?;
+        Ok(())
+    }
+
+    pub async fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        let key = self.key(self.embedder.query_prefix(), query);
+        let mut vectors = self
+            .cached(vec![key], &[query], |missing| {
+                missing
+                    .iter()
+                    .map(|query| self.embedder.embed_query(query))
+                    .collect()
+            })
+            .await?;
+        vectors.pop().context("no query embedding returned")
+    }
+
+    // one vector per chunk, in order. only the chunks not seen before go through the model,
+    // together in one batch.
+    pub async fn embed_documents(&self, chunks: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let prefix = self.embedder.document_prefix();
+        let keys = chunks.iter().map(|chunk| self.key(prefix, chunk)).collect();
+        self.cached(keys, chunks, |missing| {
+            Ok(self
+                .embedder
+                .embed_documents(missing)?
+                .to_vec2()
+                .context("unable to read embeddings")?)
+        })
+        .await
+    }
+
+    async fn cached(
+        &self,
+        keys: Vec<Thing>,
+        texts: &[&str],
+        compute: impl FnOnce(&[&str]) -> Result<Vec<Vec<f32>>>,
+    ) -> Result<Vec<Vec<f32>>> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM $ids")
+            .bind(("ids", keys.clone()))
+            .await?;
+        let found: Vec<CachedVector> = result.take(0)?;
+        let mut found = found
+            .into_iter()
+            .map(|cached| (cached.id.to_string(), cached.vector))
+            .collect::<HashMap<String, Vec<f32>>>();
+
+        let missing = keys
+            .iter()
+            .zip(texts)
+            .filter(|(key, _)| !found.contains_key(&key.to_string()))
+            .collect::<Vec<(&Thing, &&str)>>();
+        self.hits
+            .fetch_add(keys.len() - missing.len(), Ordering::Relaxed);
+        self.misses.fetch_add(missing.len(), Ordering::Relaxed);
+
+        if !missing.is_empty() {
+            let inputs = missing
+                .iter()
+                .map(|(_, text)| **text)
+                .collect::<Vec<&str>>();
+            let vectors = compute(&inputs)?;
+            if vectors.len() != missing.len() {
+                bail!(
+                    "embedding model returned {} vectors for {} texts",
+                    vectors.len(),
+                    missing.len()
+                );
+            }
+
+            let model = self.embedder.fingerprint();
+            let rows = missing
+                .iter()
+                .zip(vectors)
+                .map(|((key, _), vector)| CachedVector {
+                    id: (*key).clone(),
+                    model: model.clone(),
+                    vector,
+                    cached_at: Some(Datetime::default()),
+                })
+                .collect::<Vec<CachedVector>>();
+            for row in &rows {
+                found.insert(row.id.to_string(), row.vector.clone());
+            }
+            // the same text may have been cached concurrently, the first vector wins
+            let count = rows.len();
+            self.db
+                .query(format!("INSERT IGNORE INTO {CACHE_TABLE} $rows"))
+                .bind(("rows", rows))
+                .await?
+                .check()
+                .context("unable to store cached embeddings")?;
+            // `serve`, `watch` and the tui run for days, they cannot wait for a restart
+            let before = self.stored.fetch_add(count, Ordering::Relaxed);
+            if before / EVICT_EVERY != (before + count) / EVICT_EVERY {
+                self.evict().await?;
+            }
+        }
+
+        keys.iter()
+            .map(|key| {
+                found
+                    .get(&key.to_string())
+                    .cloned()
+                    .context("embedding missing from cache")
+            })
+            .collect()
+    }
+
+    // the exact input of the model, whitespace included: indentation and newlines change what
+    // some tokenizers see
+    fn key(&self, prefix: &str, text: &str) -> Thing {
+        let mut hasher = Sha256::new();
+        hasher.update(self.embedder.fingerprint().as_bytes());
+        hasher.update([0]);
+        hasher.update(prefix.as_bytes());
+        hasher.update(text.as_bytes());
+        let hash = format!("{:x}", hasher.finalize());
+        Thing::from((CACHE_TABLE, hash.as_str()))
+    }
+}
@@ -1,40 +1,285 @@
-use anyhow::{Context, Error as E, Result};
-use candle_core::Tensor;
+use anyhow::{bail, Context, Error as E, Result};
+use candle_core::{Module, Tensor};
 use candle_nn::VarBuilder;
-use candle_transformers::models::bert::{BertModel, Config, DTYPE};
-use hf_hub::{api::tokio::Api, Repo};
-use tokenizers::{PaddingParams, Tokenizer};
+use candle_transformers::models::{
+    bert::{BertModel, Config, DTYPE},
+    jina_bert, modernbert,
+};
+use clap::ValueEnum;
+use hf_hub::{
+    api::tokio::{Api, ApiRepo},
+    Repo,
+};
+use serde::{Deserialize, Serialize};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::utils::device;
+use crate::{
+    ai::registry::{self, Architecture},
+    utils::device,
+};
+
+// bert position embeddings stop at 512, longer inputs are cut instead of failing the forward pass
+pub(crate) const MAX_INPUT_TOKENS: usize = 512;
+// [CLS] and [SEP] (<s> and </s> for roberta) wrap every input and count against that limit
+const SPECIAL_TOKENS: usize = 2;
 
 pub trait EmbeddingEngine {
     fn get_embeddings(&self, sentence: &str) -> Result<Tensor>;
+    // embeds several texts in one forward pass, one row per text
+    fn get_embeddings_batch(&self, sentences: &[&str]) -> Result<Tensor>;
+    // length of the vectors this model produces
+    fn dimension(&self) -> usize;
+    // hub id of the model, vectors from different models cannot be compared
+    fn model_id(&self) -> &str;
+    // the model id plus every setting that changes its vectors, cached vectors are kept apart
+    // by it
+    fn fingerprint(&self) -> String {
+        self.model_id().to_string()
+    }
+    // a single text as a plain vector of `dimension()` floats
+    fn embed(&self, sentence: &str) -> Result<Vec<f32>> {
+        let vector: Vec<f32> = self.get_embeddings(sentence)?.squeeze(0)?.to_vec1()?;
+        if vector.len() != self.dimension() {
+            bail!(
+                "embedding model returned {} values, expected {}",
+                vector.len(),
+                self.dimension()
+            );
+        }
+        Ok(vector)
+    }
+    // how the token embeddings of a text are reduced to one vector
+    fn pooling(&self) -> Pooling {
+        Pooling::default()
+    }
+    // whether text is nfkc normalized before it is embedded
+    fn nfkc(&self) -> bool {
+        false
+    }
+    // prepended to search queries, e.g. "query: " for e5
+    fn query_prefix(&self) -> &str {
+        ""
+    }
+    // prepended to the chunks that are stored, e.g. "passage: " for e5
+    fn document_prefix(&self) -> &str {
+        ""
+    }
+    // a search query as a vector, the way the model was trained to see queries
+    fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        self.embed(&format!("{}{}", self.query_prefix(), query))
+    }
+    // chunks to be stored in one forward pass, one row per chunk
+    fn embed_documents(&self, chunks: &[&str]) -> Result<Tensor> {
+        let prefix = self.document_prefix();
+        let chunks = chunks
+            .iter()
+            .map(|chunk| format!("{prefix}{chunk}"))
+            .collect::<Vec<String>>();
+        let chunks = chunks.iter().map(String::as_str).collect::<Vec<&str>>();
+        self.get_embeddings_batch(&chunks)
+    }
+    // byte ranges of the tokens the model would see for `text`, without special tokens.
+    // the chunker measures and cuts text with these.
+    fn token_offsets(&self, text: &str) -> Result<Vec<(usize, usize)>>;
+    // tokens a stored chunk may have before the model cuts it off, once the special tokens and
+    // the document prefix are added
+    fn max_document_tokens(&self) -> Result<usize> {
+        let prefix = self.token_offsets(self.document_prefix())?.len();
+        Ok(MAX_INPUT_TOKENS.saturating_sub(SPECIAL_TOKENS + prefix))
+    }
+}
+
+// how the token embeddings of a text are reduced to one vector
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    // average of the real tokens, padding left out
+    #[default]
+    Mean,
+    // the embedding of the leading [CLS] token
+    Cls,
+    // element-wise maximum over the real tokens
+    Max,
+}
+
+impl Pooling {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Pooling::Mean => "mean",
+            Pooling::Cls => "cls",
+            Pooling::Max => "max",
+        }
+    }
+}
+
+// `1_Pooling/config.json` of a sentence-transformers model
+#[derive(Debug, Deserialize)]
+struct PoolingConfig {
+    #[serde(default)]
+    pooling_mode_cls_token: bool,
+    #[serde(default)]
+    pooling_mode_mean_tokens: bool,
+    #[serde(default)]
+    pooling_mode_max_tokens: bool,
+}
+
+impl PoolingConfig {
+    fn pooling(&self) -> Option<Pooling> {
+        if self.pooling_mode_cls_token {
+            Some(Pooling::Cls)
+        } else if self.pooling_mode_max_tokens {
+            Some(Pooling::Max)
+        } else if self.pooling_mode_mean_tokens {
+            Some(Pooling::Mean)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbedderOptions {
+    // nfkc normalize text before embedding it
+    pub nfkc: bool,
+    // overrides the pooling the model ships with
+    pub pooling: Option<Pooling>,
+    // override the registry's prefixes for queries and stored chunks
+    pub query_prefix: Option<String>,
+    pub document_prefix: Option<String>,
+}
+
+// the encoder behind an `Embedder`, one variant per supported architecture
+enum Encoder {
+    Bert(BertModel),
+    JinaBert(jina_bert::BertModel),
+    ModernBert(modernbert::ModernBert),
+}
+
+impl Encoder {
+    // returns the encoder and its hidden size
+    fn load(architecture: Architecture, config: &str, vb: VarBuilder) -> Result<(Self, usize)> {
+        match architecture {
+            Architecture::Bert => {
+                let config: Config = serde_json::from_str(config)?;
+                let model = BertModel::load(vb, &config)?;
+                Ok((Encoder::Bert(model), config.hidden_size))
+            }
+            Architecture::JinaBert => {
+                let config: jina_bert::Config = serde_json::from_str(config)?;
+                let model = jina_bert::BertModel::new(vb, &config)?;
+                Ok((Encoder::JinaBert(model), config.hidden_size))
+            }
+            Architecture::ModernBert => {
+                let config: modernbert::Config = serde_json::from_str(config)?;
+                // sentence-transformers exports drop the `model.` prefix candle expects
+                let vb = if vb.contains_tensor("model.embeddings.tok_embeddings.weight") {
+                    vb
+                } else {
+                    vb.rename_f(|name| name.strip_prefix("model.").unwrap_or(name).to_string())
+                };
+                let model = modernbert::ModernBert::load(vb, &config)?;
+                Ok((Encoder::ModernBert(model), config.hidden_size))
+            }
+        }
+    }
+
+    // (sentences, tokens) ids and mask in, (sentences, tokens, hidden size) out
+    fn forward(&self, token_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let embeddings = match self {
+            Encoder::Bert(model) => {
+                let token_type_ids = token_ids.zeros_like()?;
+                model.forward(token_ids, &token_type_ids, Some(attention_mask))?
+            }
+            // jina has no attention mask, `get_embeddings_batch` never pads its input
+            Encoder::JinaBert(model) => model.forward(token_ids)?,
+            Encoder::ModernBert(model) => model.forward(token_ids, attention_mask)?,
+        };
+        Ok(embeddings)
+    }
 }
 
 pub struct Embedder {
-    model: BertModel,
+    // hub id of the model, stored in the database next to the vectors
+    model_id: String,
+    model: Encoder,
     tokenizer: Tokenizer,
+    // same vocabulary without truncation, so whole documents can be measured
+    counter: Tokenizer,
+    pooling: Pooling,
+    query_prefix: String,
+    document_prefix: String,
+    // hidden size of the model, the length of every vector
+    dimension: usize,
+    // nfkc normalize text before embedding it
+    nfkc: bool,
 }
 
-async fn load_embedding_model(name: &str) -> Result<(BertModel, Tokenizer)> {
+// the pooling the model was trained with, mean when it does not say
+async fn load_pooling(api: &ApiRepo) -> Pooling {
+    let Ok(filename) = api.get("1_Pooling/config.json").await else {
+        return Pooling::default();
+    };
+    std::fs::read_to_string(filename)
+        .ok()
+        .and_then(|config| serde_json::from_str::<PoolingConfig>(&config).ok())
+        .and_then(|config| config.pooling())
+        .unwrap_or_default()
+}
+
+async fn load_embedding_model(name: &str, options: EmbedderOptions) -> Result<Embedder> {
     // Initialize the API for Hugging Face Hub and fetch model files
-    // sentence-transformers/all-MiniLM-L6-v2
     let api = Api::new()?.repo(Repo::model(name.to_string()));
     let config_filename = api.get("config.json").await?;
     let tokenizer_filename = api.get("tokenizer.json").await?;
-    let weights_filename = api.get("pytorch_model.bin").await?;
+    let pooling = load_pooling(&api).await;
 
     // Load model configuration from the downloaded JSON file
     let config = std::fs::read_to_string(config_filename)?;
-    let config: Config = serde_json::from_str(&config)?;
+    let spec = registry::lookup(name);
+    let architecture = match spec {
+        Some(spec) => spec.architecture,
+        None => registry::detect_architecture(&serde_json::from_str(&config)?)
+            .with_context(|| format!("{name} is not a supported embedding model"))?,
+    };
+    // models outside the registry get no prefixes unless they are given
+    let (query_prefix, document_prefix) = spec
+        .map(|spec| (spec.query_prefix, spec.document_prefix))
+        .unwrap_or_default();
 
     // Load the tokenizer
     let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
-    // Load the model weights and initialize the BERT model
-    let vb = VarBuilder::from_pth(&weights_filename, DTYPE, &device(false)?)?;
-    let model = BertModel::load(vb, &config)?;
+    // Load the model weights and initialize the encoder
+    let vb = registry::load_weights(&api, DTYPE, &device(false)?).await?;
+    let (model, dimension) = Encoder::load(architecture, &config, vb)
+        .with_context(|| format!("Unable to load {name} as {architecture:?}"))?;
+
+    configure_tokenizer(&mut tokenizer)?;
 
+    let mut counter = tokenizer.clone();
+    counter.with_padding(None);
+    counter.with_truncation(None).map_err(E::msg)?;
+
+    Ok(Embedder {
+        model_id: name.to_string(),
+        model,
+        tokenizer,
+        counter,
+        pooling: options.pooling.unwrap_or(pooling),
+        query_prefix: options
+            .query_prefix
+            .unwrap_or_else(|| query_prefix.to_string()),
+        document_prefix: options
+            .document_prefix
+            .unwrap_or_else(|| document_prefix.to_string()),
+        dimension,
+        nfkc: options.nfkc,
+    })
+}
+
+// pads batches to their longest text and cuts inputs the model cannot take
+pub(crate) fn configure_tokenizer(tokenizer: &mut Tokenizer) -> Result<()> {
     // Set padding strategy for the tokenizer
     if let Some(pp) = tokenizer.get_padding_mut() {
         pp.strategy = tokenizers::PaddingStrategy::BatchLongest
@@ -46,64 +291,88 @@ async fn load_embedding_model(name: &str) -> Result<(BertModel, Tokenizer)> {
         tokenizer.with_padding(Some(pp));
     }
 
-    // Return the model and tokenizer
-    Ok((model, tokenizer))
+    if tokenizer.get_truncation().is_none() {
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: MAX_INPUT_TOKENS,
+                ..Default::default()
+            }))
+            .map_err(E::msg)?;
+    }
+    Ok(())
+}
+
+// Optionally fold compatibility characters (full-width forms, ligatures, superscripts) into
+// their plain equivalents. Everything else, accents, CJK and emoji included, is passed to the
+// tokenizer untouched.
+pub(crate) fn normalize(text: &str, nfkc: bool) -> String {
+    if nfkc {
+        text.nfkc().collect()
+    } else {
+        text.to_string()
+    }
+}
+
+// Tokenize all sentences at once. `BatchLongest` pads every sentence to the longest one, the
+// attention mask marks which positions are real tokens. Both tensors are
+// (number of sentences, number of tokens).
+pub(crate) fn encode_batch(
+    tokenizer: &Tokenizer,
+    sentences: Vec<String>,
+) -> Result<(Tensor, Tensor)> {
+    let tokens = tokenizer
+        .encode_batch(sentences, true)
+        .map_err(E::msg)
+        .context("Unable to encode sentences")?;
+
+    // Convert the token ids and attention masks into tensors, one row per sentence.
+    let device = device(false)?;
+    let token_ids = tokens
+        .iter()
+        .map(|tokens| Ok(Tensor::new(tokens.get_ids(), &device)?))
+        .collect::<Result<Vec<_>>>()
+        .context("Unable to get token ids")?;
+    let attention_mask = tokens
+        .iter()
+        .map(|tokens| Ok(Tensor::new(tokens.get_attention_mask(), &device)?))
+        .collect::<Result<Vec<_>>>()
+        .context("Unable to get attention mask")?;
+
+    let token_ids = Tensor::stack(&token_ids, 0).context("Unable to stack token ids")?;
+    let attention_mask =
+        Tensor::stack(&attention_mask, 0).context("Unable to stack attention mask")?;
+    Ok((token_ids, attention_mask))
 }
 
 impl EmbeddingEngine for Embedder {
     fn get_embeddings(&self, sentence: &str) -> Result<Tensor> {
-        // Preprocess the input sentence by filtering out non-ASCII characters.
-        // This ensures the input is compatible with the tokenizer.
-        let sentence = sentence
-            .chars()
-            .filter(|c| c.is_ascii()) // Keep only ASCII characters
-            .collect::<String>(); // Convert the filtered characters back into a string
-
-        // Tokenize the sentence using the tokenizer.
-        // `encode_batch` tokenizes a batch of sentences (here, just one sentence).
-        let tokens = self
-            .tokenizer
-            .encode_batch(vec![sentence], true) // Tokenize the sentence
-            .map_err(E::msg) // Convert errors to a custom error type
-            .context("Unable to encode sentence")?; // Add context to the error if tokenization fails
-
-        // Convert the tokenized input into tensor format.
-        // Each token is mapped to its corresponding ID and converted into a tensor.
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec(); // Get token IDs as a vector
-                Ok(Tensor::new(tokens.as_slice(), &device(false)?)?) // Create a tensor from the token IDs
-            })
-            .collect::<Result<Vec<_>>>() // Collect all token tensors into a vector
-            .context("Unable to get token ids")?; // Add context to the error if tensor creation fails
-
-        // Stack the token tensors into a single tensor.
-        // This is necessary for batch processing (even if there's only one sentence).
-        let token_ids = Tensor::stack(&token_ids, 0).context("Unable to stack token ids")?;
-
-        // Create a tensor of zeros with the same shape as `token_ids`.
-        // This is used as `token_type_ids` (typically for segmenting sentences in models like BERT).
-        let token_type_ids = token_ids
-            .zeros_like()
-            .context("Unable to get token type ids")?;
-
-        // Pass the token IDs and token type IDs through the model to get embeddings.
+        self.get_embeddings_batch(&[sentence])
+    }
+
+    fn get_embeddings_batch(&self, sentences: &[&str]) -> Result<Tensor> {
+        // jina cannot mask its attention, so padding would leak into the shorter texts. a
+        // batch of one has no padding.
+        if matches!(self.model, Encoder::JinaBert(_)) && sentences.len() > 1 {
+            let rows = sentences
+                .iter()
+                .map(|sentence| self.get_embeddings_batch(&[*sentence]))
+                .collect::<Result<Vec<Tensor>>>()?;
+            return Tensor::cat(&rows, 0).context("Unable to concatenate embeddings");
+        }
+
+        let sentences = sentences.iter().map(|s| normalize(s, self.nfkc)).collect();
+        let (token_ids, attention_mask) = encode_batch(&self.tokenizer, sentences)?;
+
+        // One forward pass for the whole batch, padding is masked out of the attention.
         let embeddings = self
             .model
-            .forward(&token_ids, &token_type_ids, None) // Forward pass through the model
-            .context("Unable to get embeddings")?; // Add context to the error if the forward pass fails
+            .forward(&token_ids, &attention_mask)
+            .context("Unable to get embeddings")?;
 
-        // Get the dimensions of the embeddings tensor.
-        // The shape is (number of sentences, number of tokens, hidden size).
-        let (_n_sentence, n_tokens, _hidden_size) = embeddings
-            .dims3()
-            .context("Unable to get embeddings dimensions")?;
-
-        // Compute the mean embedding for each sentence by summing over the tokens
-        // and dividing by the number of tokens.
-        let embeddings =
-            (embeddings.sum(1)? / (n_tokens as f64)).context("Unable to get embeddings sum")?;
+        // Reduce the token embeddings of each sentence to one vector, leaving out the padding.
+        // The shape goes from (sentences, tokens, hidden size) to (sentences, hidden size).
+        let embeddings = pool(&embeddings, &attention_mask, self.pooling)
+            .context("Unable to pool embeddings")?;
 
         // Normalize the embeddings by dividing each embedding vector by its L2 norm.
         // This ensures the embeddings are unit vectors, which is often useful for similarity calculations.
@@ -114,11 +383,104 @@ impl EmbeddingEngine for Embedder {
         // Return the final normalized embeddings tensor.
         Ok(embeddings)
     }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn fingerprint(&self) -> String {
+        let nfkc = if self.nfkc { "+nfkc" } else { "" };
+        format!(
+            "{}#{:?}{}?query={:?}&document={:?}",
+            self.model_id, self.pooling, nfkc, self.query_prefix, self.document_prefix
+        )
+    }
+
+    fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
+    fn nfkc(&self) -> bool {
+        self.nfkc
+    }
+
+    fn query_prefix(&self) -> &str {
+        &self.query_prefix
+    }
+
+    fn document_prefix(&self) -> &str {
+        &self.document_prefix
+    }
+
+    fn token_offsets(&self, text: &str) -> Result<Vec<(usize, usize)>> {
+        let encoding = self
+            .counter
+            .encode(text, false)
+            .map_err(E::msg)
+            .context("Unable to encode text")?;
+        Ok(encoding.get_offsets().to_vec())
+    }
 }
 
 impl Embedder {
-    pub async fn new(name: &str) -> Result<Self> {
-        let (model, tokenizer) = load_embedding_model(name).await?;
-        Ok(Self { model, tokenizer })
+    pub async fn new(name: &str, options: EmbedderOptions) -> Result<Self> {
+        load_embedding_model(name, options).await
+    }
+}
+
+// `embeddings` is (sentences, tokens, hidden size), `attention_mask` is 1 for real tokens
+fn pool(embeddings: &Tensor, attention_mask: &Tensor, pooling: Pooling) -> Result<Tensor> {
+    let mask = attention_mask.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+    let pooled = match pooling {
+        Pooling::Mean => {
+            let summed = embeddings.broadcast_mul(&mask)?.sum(1)?;
+            summed.broadcast_div(&mask.sum(1)?)?
+        }
+        Pooling::Cls => embeddings.narrow(1, 0, 1)?.squeeze(1)?,
+        Pooling::Max => {
+            // push padding far below any real value before taking the maximum
+            let padding = mask.affine(1e9, -1e9)?;
+            embeddings.broadcast_add(&padding)?.max(1)?
+        }
+    };
+    Ok(pooled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    // two texts of two-dimensional token embeddings, the second one padded by one token whose
+    // zeros would win a maximum and shift a mean
+    fn batch() -> Result<(Tensor, Tensor)> {
+        let embeddings = Tensor::new(
+            &[
+                [[1f32, 2.], [3., 4.], [5., 6.]],
+                [[-5., -3.], [-1., -7.], [0., 0.]],
+            ],
+            &Device::Cpu,
+        )?;
+        let mask = Tensor::new(&[[1u32, 1, 1], [1, 1, 0]], &Device::Cpu)?;
+        Ok((embeddings, mask))
+    }
+
+    #[test]
+    fn pooling_leaves_out_padding() -> Result<()> {
+        let cases = [
+            (Pooling::Mean, [[3f32, 4.], [-3., -5.]]),
+            (Pooling::Max, [[5., 6.], [-1., -3.]]),
+            (Pooling::Cls, [[1., 2.], [-5., -3.]]),
+        ];
+        let (embeddings, mask) = batch()?;
+        for (pooling, expected) in cases {
+            let pooled: Vec<Vec<f32>> = pool(&embeddings, &mask, pooling)?.to_vec2()?;
+            assert_eq!(pooled, expected, "{}", pooling.as_str());
+        }
+        Ok(())
     }
 }
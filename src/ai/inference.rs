@@ -9,7 +9,18 @@ use hf_hub::{api::sync::Api, Repo};
 use tokenizers::Tokenizer;
 
 pub trait InferenceEngine {
-    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<String>;
+    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<String> {
+        self.run_stream(prompt, sample_len, &mut |_| true)
+    }
+
+    // same as `run`, but hands every decoded token to `on_token` as soon as it is sampled.
+    // returning false from `on_token` stops generation early (e.g. the listener went away).
+    fn run_stream(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        on_token: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<String>;
 }
 
 pub struct TextGeneration {
@@ -22,12 +33,17 @@ pub struct TextGeneration {
     repeat_last_n: usize,
 }
 
-pub async fn load_inference_model(name: &str, device: &Device) -> Result<(QMixFormer, Tokenizer)> {
+pub fn load_tokenizer(name: &str) -> Result<Tokenizer> {
     let api = Api::new()?.repo(Repo::model(name.to_string()));
     let tokenizer_filename = api.get("tokenizer.json")?;
+    Tokenizer::from_file(tokenizer_filename).map_err(E::msg)
+}
+
+pub async fn load_inference_model(name: &str, device: &Device) -> Result<(QMixFormer, Tokenizer)> {
+    let api = Api::new()?.repo(Repo::model(name.to_string()));
     let weights_filename = api.get("model-q4k.gguf")?;
 
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+    let tokenizer = load_tokenizer(name)?;
     let config = Config::v2();
     let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
         &weights_filename,
@@ -64,7 +80,12 @@ impl TextGeneration {
 }
 
 impl InferenceEngine for TextGeneration {
-    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<String> {
+    fn run_stream(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        on_token: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<String> {
         let tokens = self.tokenizer.encode(prompt, true).map_err(E::msg)?;
         if tokens.is_empty() {
             anyhow::bail!("Empty prompts are not supported in the phi model.")
@@ -104,6 +125,9 @@ impl InferenceEngine for TextGeneration {
             }
             let token = self.tokenizer.decode(&[next_token], true).map_err(E::msg)?;
             response += &token;
+            if !on_token(&token) {
+                break;
+            }
         }
         let dt = start_gen.elapsed();
         Ok(response.trim().to_string())
@@ -1,59 +1,211 @@
+pub mod cache;
 pub mod embedding;
 pub mod inference;
+pub mod registry;
+pub mod sparse;
 pub mod worker_pool;
 
-use anyhow::Result;
-use serde_json::json;
+use anyhow::{Error as E, Result};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokenizers::Tokenizer;
+use tokio::sync::{mpsc, Mutex};
 
 use crate::{
-    ai::worker_pool::{InferenceResult, WorkerPool},
-    data::database::VectorIndex,
+    ai::worker_pool::{InferenceResult, StreamEvent, WorkerPool},
+    data::database::Turn,
+    qa::Passage,
+    router::session::SessionKey,
 };
 pub use embedding::EmbeddingEngine;
+pub use sparse::SparseEmbeddingEngine;
+
+// tokens generated per answer
+pub const SAMPLE_LEN: usize = 400;
+// a rewritten follow-up question is a single line, this only caps runaway generations
+const REWRITE_SAMPLE_LEN: usize = 64;
 
 pub struct AI {
     pub embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
     pub inference_pool: Arc<Mutex<WorkerPool>>,
+    tokenizer: Arc<Tokenizer>,
 }
 
 impl AI {
     pub fn new(
         embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
         inference_pool: Arc<Mutex<WorkerPool>>,
+        tokenizer: Arc<Tokenizer>,
     ) -> Self {
         AI {
             embedder,
             inference_pool,
+            tokenizer,
         }
     }
 
-    pub async fn answer_question_with_context(
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        let encoding = self.tokenizer.encode(text, true).map_err(E::msg)?;
+        Ok(encoding.len())
+    }
+
+    // size of the full prompt the model would see for these passages
+    pub fn prompt_tokens(
         &self,
         query: &str,
-        references: &[VectorIndex],
-    ) -> Result<InferenceResult> {
-        let mut context = Vec::new();
-        for reference in references {
-            context.push(json!({
-                "content": reference.content_chunk,
-                "metadata": reference.metadata
-            }));
+        references: &[Passage],
+        history: &[Turn],
+    ) -> Result<usize> {
+        self.count_tokens(&build_prompt(query, references, history))
+    }
+
+    // turns a follow-up like "what about linux?" into a question that can be searched for
+    // on its own, using the earlier turns of the session. first questions pass through, and
+    // so do questions that do not fit `budget` tokens even without the history.
+    pub async fn rewrite_query(
+        &self,
+        query: &str,
+        history: &[Turn],
+        budget: usize,
+        session_id: &SessionKey,
+    ) -> Result<String> {
+        if history.is_empty() {
+            return Ok(query.to_string());
         }
+        let Some(prompt) = self.fit_rewrite_prompt(query, history, budget)? else {
+            return Ok(query.to_string());
+        };
+        let result = self
+            .inference_pool
+            .lock()
+            .await
+            .accept(prompt, REWRITE_SAMPLE_LEN, session_id.as_str())
+            .await?;
+        let rewritten = result.0.trim();
+        if rewritten.is_empty() {
+            Ok(query.to_string())
+        } else {
+            Ok(rewritten.to_string())
+        }
+    }
+
+    // the rewrite prompt inside `budget` minus the tokens reserved for the rewrite, like
+    // `fit_to_budget` does for answers: the oldest turns go first, then the answer of the
+    // last turn is cut. `None` when not even that fits.
+    fn fit_rewrite_prompt(
+        &self,
+        query: &str,
+        history: &[Turn],
+        budget: usize,
+    ) -> Result<Option<String>> {
+        let budget = budget.saturating_sub(REWRITE_SAMPLE_LEN);
+        let mut history = history.to_vec();
+        let mut prompt = build_rewrite_prompt(query, &history);
+        let mut tokens = self.count_tokens(&prompt)?;
+        while tokens > budget && history.len() > 1 {
+            history.remove(0);
+            prompt = build_rewrite_prompt(query, &history);
+            tokens = self.count_tokens(&prompt)?;
+        }
+
+        if tokens > budget {
+            if let Some(turn) = history.first_mut() {
+                let overflow = tokens - budget;
+                let keep = self.count_tokens(&turn.answer)?.saturating_sub(overflow);
+                turn.answer = self.truncate_to_tokens(&turn.answer, keep)?;
+                prompt = build_rewrite_prompt(query, &history);
+                tokens = self.count_tokens(&prompt)?;
+            }
+        }
+        Ok((tokens <= budget).then_some(prompt))
+    }
 
-        let context_str = serde_json::to_string(&context)?;
-        let prompt = format!(
-            "You are a friendly AI agent. Context: {} Query: {}",
-            context_str, query
-        );
+    // cuts `text` down to its first `max_tokens` tokens, on a token boundary
+    pub fn truncate_to_tokens(&self, text: &str, max_tokens: usize) -> Result<String> {
+        let encoding = self.tokenizer.encode(text, false).map_err(E::msg)?;
+        if encoding.len() <= max_tokens {
+            return Ok(text.to_string());
+        }
+        let mut end = match max_tokens {
+            0 => 0,
+            n => encoding.get_offsets()[n - 1].1,
+        };
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Ok(text[..end].to_string())
+    }
 
+    pub async fn answer_question_with_context(
+        &self,
+        query: &str,
+        references: &[Passage],
+        history: &[Turn],
+        session_id: &SessionKey,
+    ) -> Result<InferenceResult> {
+        let prompt = build_prompt(query, references, history);
         let result = self
             .inference_pool
             .lock()
             .await
-            .accept(prompt, 400, "<SESSION_ID>")
+            .accept(prompt, SAMPLE_LEN, session_id.as_str())
             .await?;
         Ok(result)
     }
+
+    // streaming variant, the pool lock is only held while the job is queued
+    pub async fn answer_question_with_context_stream(
+        &self,
+        query: &str,
+        references: &[Passage],
+        history: &[Turn],
+        session_id: &SessionKey,
+    ) -> Result<mpsc::Receiver<StreamEvent>> {
+        let prompt = build_prompt(query, references, history);
+        let rx = self
+            .inference_pool
+            .lock()
+            .await
+            .accept_stream(prompt, SAMPLE_LEN, session_id.as_str())
+            .await?;
+        Ok(rx)
+    }
+}
+
+// passages are numbered so the model can cite them as [1], [2], ...
+fn build_prompt(query: &str, references: &[Passage], history: &[Turn]) -> String {
+    let context = references
+        .iter()
+        .map(|p| format!("[{}] ({}) {}", p.source.index, p.source.label(), p.text))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let conversation = if history.is_empty() {
+        String::new()
+    } else {
+        format!("Conversation so far:\n{}\n", render_history(history))
+    };
+
+    format!(
+        "You are a friendly AI agent. Answer the query using only the numbered context \
+         passages and cite the ones you use like [1] or [2].\n{}Context:\n{}\nQuery: {}\nAnswer:",
+        conversation, context, query
+    )
+}
+
+fn build_rewrite_prompt(query: &str, history: &[Turn]) -> String {
+    format!(
+        "Rewrite the follow-up question as a standalone question that can be understood \
+         without the conversation. Reply with the question only.\n{}\nFollow-up question: \
+         {}\nStandalone question:",
+        render_history(history),
+        query
+    )
+}
+
+fn render_history(history: &[Turn]) -> String {
+    history
+        .iter()
+        .map(|turn| format!("User: {}\nAssistant: {}", turn.question, turn.answer))
+        .collect::<Vec<String>>()
+        .join("\n")
 }
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use candle_core::{DType, Device};
+use candle_nn::VarBuilder;
+use clap::ValueEnum;
+use hf_hub::api::tokio::ApiRepo;
+use serde::{Deserialize, Serialize};
+
+// used when neither the command line nor the database names a model
+pub const DEFAULT_MODEL: &str = "BAAI/bge-small-en-v1.5";
+
+// what databases were embedded with before the model was recorded. it is a splade model whose
+// token embeddings were mean pooled, kept so those databases still open with their own model.
+pub const LEGACY_MODEL: &str = "ibm-granite/granite-embedding-30m-sparse";
+
+// the encoder families `Embedder` can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Architecture {
+    // bert and roberta style encoders: minilm, bge, e5, granite
+    Bert,
+    // jina's bert with alibi attention instead of position embeddings
+    JinaBert,
+    // modernbert with rotary embeddings and local attention
+    ModernBert,
+}
+
+pub struct ModelSpec {
+    pub id: &'static str,
+    pub architecture: Architecture,
+    // prepended to search queries, asymmetric models were trained with them
+    pub query_prefix: &'static str,
+    // prepended to the chunks that are stored
+    pub document_prefix: &'static str,
+}
+
+// models known to work. other hub ids are loaded by the architecture their config.json names.
+pub const MODELS: &[ModelSpec] = &[
+    ModelSpec {
+        id: "ibm-granite/granite-embedding-30m-sparse",
+        architecture: Architecture::Bert,
+        query_prefix: "",
+        document_prefix: "",
+    },
+    ModelSpec {
+        id: "sentence-transformers/all-MiniLM-L6-v2",
+        architecture: Architecture::Bert,
+        query_prefix: "",
+        document_prefix: "",
+    },
+    ModelSpec {
+        id: "BAAI/bge-small-en-v1.5",
+        architecture: Architecture::Bert,
+        query_prefix: "Represent this sentence for searching relevant passages: ",
+        document_prefix: "",
+    },
+    ModelSpec {
+        id: "intfloat/e5-small-v2",
+        architecture: Architecture::Bert,
+        query_prefix: "query: ",
+        document_prefix: "passage: ",
+    },
+    ModelSpec {
+        id: "jinaai/jina-embeddings-v2-small-en",
+        architecture: Architecture::JinaBert,
+        query_prefix: "",
+        document_prefix: "",
+    },
+    ModelSpec {
+        id: "jinaai/jina-embeddings-v2-base-en",
+        architecture: Architecture::JinaBert,
+        query_prefix: "",
+        document_prefix: "",
+    },
+    ModelSpec {
+        id: "nomic-ai/modernbert-embed-base",
+        architecture: Architecture::ModernBert,
+        query_prefix: "search_query: ",
+        document_prefix: "search_document: ",
+    },
+];
+
+pub fn lookup(id: &str) -> Option<&'static ModelSpec> {
+    MODELS.iter().find(|spec| spec.id == id)
+}
+
+// picks the architecture of a model outside the registry from its config.json
+pub fn detect_architecture(config: &serde_json::Value) -> Option<Architecture> {
+    let architectures = config["architectures"]
+        .as_array()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|n| n.as_str())
+                .collect::<Vec<&str>>()
+        })
+        .unwrap_or_default();
+    if architectures
+        .iter()
+        .any(|name| name.starts_with("JinaBert"))
+    {
+        return Some(Architecture::JinaBert);
+    }
+    match config["model_type"].as_str() {
+        Some("modernbert") => Some(Architecture::ModernBert),
+        Some("bert" | "roberta" | "xlm-roberta") => Some(Architecture::Bert),
+        _ => None,
+    }
+}
+
+// prefers safetensors, older checkpoints only ship `pytorch_model.bin`
+pub async fn load_weights(
+    api: &ApiRepo,
+    dtype: DType,
+    device: &Device,
+) -> Result<VarBuilder<'static>> {
+    if let Ok(path) = api.get("model.safetensors").await {
+        // safety: the file is memory mapped and must not change while the model is loaded,
+        // the hf-hub cache never rewrites a downloaded file in place
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[path], dtype, device)? };
+        return Ok(vb);
+    }
+    let path = api
+        .get("pytorch_model.bin")
+        .await
+        .context("model has neither model.safetensors nor pytorch_model.bin")?;
+    Ok(VarBuilder::from_pth(&path, dtype, device)?)
+}
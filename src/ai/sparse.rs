@@ -0,0 +1,151 @@
+use anyhow::{Context, Error as E, Result};
+use candle_core::{Module, Tensor};
+use candle_nn::{layer_norm, linear, LayerNorm, Linear, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use hf_hub::{api::tokio::Api, Repo};
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+use crate::{
+    ai::{
+        embedding::{configure_tokenizer, encode_batch, normalize, EmbedderOptions},
+        registry,
+    },
+    utils::device,
+};
+
+// vocabulary token id -> weight, only the terms the model activated
+pub type SparseVector = HashMap<u32, f32>;
+
+pub trait SparseEmbeddingEngine {
+    fn get_sparse_embeddings_batch(&self, sentences: &[&str]) -> Result<Vec<SparseVector>>;
+    fn get_sparse_embedding(&self, sentence: &str) -> Result<SparseVector> {
+        let mut vectors = self.get_sparse_embeddings_batch(&[sentence])?;
+        vectors.pop().context("no sparse embedding returned")
+    }
+}
+
+// the masked language modelling head on top of the encoder, it scores every vocabulary entry
+// for every token position
+struct MlmHead {
+    dense: Linear,
+    layer_norm: LayerNorm,
+    decoder: Linear,
+}
+
+impl MlmHead {
+    // bert checkpoints name the head `cls.predictions`, roberta ones `lm_head`. the decoder
+    // weight is often tied to the word embeddings and then not stored separately.
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (head, dense, norm, embeddings) = if vb.contains_tensor("lm_head.dense.weight") {
+            let model_type = config.model_type.as_deref().unwrap_or("roberta");
+            (
+                vb.pp("lm_head"),
+                "dense",
+                "layer_norm",
+                format!("{model_type}.embeddings.word_embeddings"),
+            )
+        } else {
+            (
+                vb.pp("cls.predictions"),
+                "transform.dense",
+                "transform.LayerNorm",
+                "bert.embeddings.word_embeddings".to_string(),
+            )
+        };
+
+        let hidden = config.hidden_size;
+        let vocab = config.vocab_size;
+        let decoder_weight = head
+            .pp("decoder")
+            .get((vocab, hidden), "weight")
+            .or_else(|_| vb.pp(embeddings).get((vocab, hidden), "weight"))?;
+        let decoder_bias = head
+            .get(vocab, "bias")
+            .or_else(|_| head.pp("decoder").get(vocab, "bias"))?;
+
+        Ok(Self {
+            dense: linear(hidden, hidden, head.pp(dense))?,
+            layer_norm: layer_norm(hidden, config.layer_norm_eps, head.pp(norm))?,
+            decoder: Linear::new(decoder_weight, Some(decoder_bias)),
+        })
+    }
+
+    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.dense.forward(hidden_states)?.gelu_erf()?;
+        let hidden_states = self.layer_norm.forward(&hidden_states)?;
+        Ok(self.decoder.forward(&hidden_states)?)
+    }
+}
+
+// splade: every vocabulary entry gets the weight `max over tokens of log(1 + relu(logit))`,
+// so a text is represented by the terms it contains and the ones the model expands it to
+pub struct SparseEmbedder {
+    model: BertModel,
+    head: MlmHead,
+    tokenizer: Tokenizer,
+    nfkc: bool,
+}
+
+impl SparseEmbedder {
+    pub async fn new(name: &str, options: EmbedderOptions) -> Result<Self> {
+        let api = Api::new()?.repo(Repo::model(name.to_string()));
+        let config_filename = api.get("config.json").await?;
+        let tokenizer_filename = api.get("tokenizer.json").await?;
+
+        let config = std::fs::read_to_string(config_filename)?;
+        let config: Config = serde_json::from_str(&config)?;
+
+        let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        configure_tokenizer(&mut tokenizer)?;
+
+        // the encoder sits under `bert.` in bert masked lm checkpoints, under the model type
+        // (e.g. `roberta.`) otherwise, which `BertModel::load` already falls back to
+        let vb = registry::load_weights(&api, DTYPE, &device(false)?).await?;
+        let model = BertModel::load(vb.pp("bert"), &config)
+            .or_else(|_| BertModel::load(vb.clone(), &config))
+            .context("Unable to load sparse encoder")?;
+        let head = MlmHead::load(vb, &config).context("Unable to load masked lm head")?;
+
+        Ok(Self {
+            model,
+            head,
+            tokenizer,
+            nfkc: options.nfkc,
+        })
+    }
+}
+
+impl SparseEmbeddingEngine for SparseEmbedder {
+    fn get_sparse_embeddings_batch(&self, sentences: &[&str]) -> Result<Vec<SparseVector>> {
+        let sentences = sentences.iter().map(|s| normalize(s, self.nfkc)).collect();
+        let (token_ids, attention_mask) = encode_batch(&self.tokenizer, sentences)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        let hidden_states = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))
+            .context("Unable to run sparse encoder")?;
+        // (sentences, tokens, vocabulary)
+        let logits = self.head.forward(&hidden_states)?;
+
+        // padding positions must not contribute, their weights are zeroed before the max
+        let mask = attention_mask.to_dtype(logits.dtype())?.unsqueeze(2)?;
+        let weights = (logits.relu()? + 1.0)?
+            .log()?
+            .broadcast_mul(&mask)?
+            .max(1)?
+            .to_vec2::<f32>()?;
+
+        Ok(weights
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .enumerate()
+                    .filter(|(_, weight)| *weight > 0.0)
+                    .map(|(term, weight)| (term as u32, weight))
+                    .collect()
+            })
+            .collect())
+    }
+}
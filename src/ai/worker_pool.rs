@@ -11,16 +11,32 @@ use tokio::{
     time::Instant,
 };
 
-use crate::ai::inference::{InferenceEngine, TextGeneration};
+use tokenizers::Tokenizer;
+
+use crate::ai::inference::{load_tokenizer, InferenceEngine, TextGeneration};
 
 const MAX_SESSION: usize = 10;
 const SESSION_TTL: Duration = Duration::from_secs(30 * 60);
+pub const STREAM_BUFFER: usize = 64;
 
 pub struct InferenceJob {
     prompt: String,
     session_id: String,
     sample_len: usize,
-    reply_tx: oneshot::Sender<InferenceResult>,
+    reply_tx: Reply,
+}
+
+// where the worker sends its output: the whole answer at once, or token by token
+enum Reply {
+    Once(oneshot::Sender<InferenceResult>),
+    Stream(mpsc::Sender<StreamEvent>),
+}
+
+#[derive(Debug)]
+pub enum StreamEvent {
+    Token(String),
+    Done(InferenceResult),
+    Error(String),
 }
 
 #[derive(Debug)]
@@ -47,6 +63,7 @@ pub struct WorkerPool {
     pub session_sticky_map: HashMap<String, Sticky>,
     session_order: VecDeque<String>, // LRU cache
     next: usize,
+    tokenizer: Arc<Tokenizer>,
 }
 
 impl Worker {
@@ -74,13 +91,36 @@ impl Worker {
                 None => break,
             };
 
-            let result = self.inference_engine.run(&job.prompt, job.sample_len);
-            let inference_result: String = match result {
-                Ok(result) => result,
-                Err(e) => format!("id: {}, inference error: {}", self.id, e),
-            };
-            // send back to oneshot channel
-            let _ = job.reply_tx.send(InferenceResult(inference_result));
+            match job.reply_tx {
+                Reply::Once(reply_tx) => {
+                    let result = self.inference_engine.run(&job.prompt, job.sample_len);
+                    let inference_result: String = match result {
+                        Ok(result) => result,
+                        Err(e) => format!("id: {}, inference error: {}", self.id, e),
+                    };
+                    // send back to oneshot channel
+                    let _ = reply_tx.send(InferenceResult(inference_result));
+                }
+                Reply::Stream(reply_tx) => {
+                    // a failed send means the receiver was dropped, so stop generating
+                    let result = self.inference_engine.run_stream(
+                        &job.prompt,
+                        job.sample_len,
+                        &mut |token| {
+                            reply_tx
+                                .blocking_send(StreamEvent::Token(token.to_string()))
+                                .is_ok()
+                        },
+                    );
+                    let event = match result {
+                        Ok(result) => StreamEvent::Done(InferenceResult(result)),
+                        Err(e) => {
+                            StreamEvent::Error(format!("id: {}, inference error: {}", self.id, e))
+                        }
+                    };
+                    let _ = reply_tx.blocking_send(event);
+                }
+            }
         }
     }
 }
@@ -113,9 +153,15 @@ impl WorkerPool {
             session_order: VecDeque::new(),
             session_sticky_map: HashMap::new(),
             next: 0,
+            tokenizer: Arc::new(load_tokenizer(name)?),
         })
     }
 
+    // the tokenizer the workers generate with, for sizing prompts before they are queued
+    pub fn tokenizer(&self) -> Arc<Tokenizer> {
+        self.tokenizer.clone()
+    }
+
     // updating LRU Cache
     fn hit_session(&mut self, session_id: &str, worker: usize) {
         let now = Instant::now();
@@ -161,6 +207,22 @@ impl WorkerPool {
         }
     }
 
+    fn pick_worker(&mut self, session_id: &str) -> usize {
+        self.prune_stale();
+        let worker = match self.session_sticky_map.get(session_id) {
+            // enable sticky session for workers
+            Some(sticky) => sticky.worker,
+            None => {
+                // load balance
+                let idx = self.next % self.workers.len();
+                self.next = self.next.wrapping_add(1);
+                idx
+            }
+        };
+        self.hit_session(session_id, worker);
+        worker
+    }
+
     pub async fn accept(
         &mut self,
         prompt: String,
@@ -172,21 +234,33 @@ impl WorkerPool {
             session_id: session_id.to_string(),
             prompt,
             sample_len,
-            reply_tx,
+            reply_tx: Reply::Once(reply_tx),
         };
 
-        let worker_id = match self.session_sticky_map.get(session_id) {
-            // enable sticky session for workers
-            Some(sticky) => sticky.worker,
-            None => {
-                // load balance
-                let idx = self.next % self.workers.len();
-                self.next = self.next.wrapping_add(1);
-                idx
-            }
-        };
+        let worker_id = self.pick_worker(session_id);
         self.workers[worker_id].send(job).await?;
         let result = reply_rx.await?;
         Ok(result)
     }
+
+    // queues the job and returns straight away, tokens arrive on the receiver as
+    // they are decoded and the stream ends with either `Done` or `Error`.
+    pub async fn accept_stream(
+        &mut self,
+        prompt: String,
+        sample_len: usize,
+        session_id: &str,
+    ) -> anyhow::Result<mpsc::Receiver<StreamEvent>> {
+        let (reply_tx, reply_rx) = mpsc::channel(STREAM_BUFFER);
+        let job = InferenceJob {
+            session_id: session_id.to_string(),
+            prompt,
+            sample_len,
+            reply_tx: Reply::Stream(reply_tx),
+        };
+
+        let worker_id = self.pick_worker(session_id);
+        self.workers[worker_id].send(job).await?;
+        Ok(reply_rx)
+    }
 }
@@ -1,4 +1,3 @@
-
 use crate::{ai::AI, data::database::VDB};
 
 use std::sync::Arc;
@@ -0,0 +1,220 @@
+use crate::{
+    ai::AI,
+    cli::Commands,
+    data::{
+        chunker::ChunkOptions,
+        database::{IngestStatus, Ingested, VDB},
+        ingest::{ingest_dir, ingest_note, ingest_path, FileOutcome, FileReport, WalkOptions},
+        retrieval::RetrievalOptions,
+        watch::watch_dir,
+    },
+    qa::answer_query,
+};
+use anyhow::{Context, Result};
+use std::{process::ExitCode, sync::Arc};
+
+// exit codes for the one-shot commands, so scripts can branch on them
+pub const EXIT_OK: u8 = 0;
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_NOT_FOUND: u8 = 3;
+
+// runs a single non-interactive command, prints the result to stdout and
+// reports failures on stderr with a matching exit code.
+pub async fn run_command(
+    command: Commands,
+    vdb: Arc<VDB>,
+    ai: Option<Arc<AI>>,
+    options: RetrievalOptions,
+    chunking: ChunkOptions,
+) -> ExitCode {
+    let code = match execute(command, &vdb, ai.as_ref(), &options, &chunking).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            EXIT_FAILURE
+        }
+    };
+    ExitCode::from(code)
+}
+
+async fn execute(
+    command: Commands,
+    vdb: &Arc<VDB>,
+    ai: Option<&Arc<AI>>,
+    options: &RetrievalOptions,
+    chunking: &ChunkOptions,
+) -> Result<u8> {
+    match command {
+        Commands::Ask { query, session } => {
+            let query = query.join(" ");
+            if query.trim().is_empty() {
+                eprintln!("error: query is empty");
+                return Ok(EXIT_USAGE);
+            }
+            let ai = ai.context("inference model is not loaded")?;
+            let session = session.unwrap_or_default();
+            let answer = answer_query(&query, vdb, ai, options, &session).await?;
+            eprintln!("session: {}", answer.session_id);
+            if answer.context.is_truncated() {
+                eprintln!(
+                    "note: context trimmed to {} tokens ({} passages, {} turns dropped{})",
+                    answer.context.budget,
+                    answer.context.dropped.len(),
+                    answer.context.dropped_turns,
+                    match answer.context.shortened {
+                        Some(index) => format!(", [{}] shortened", index),
+                        None => String::new(),
+                    }
+                );
+            }
+            println!("{}", answer);
+            if !answer.sources.is_empty() {
+                println!("\nSources:");
+                for source in &answer.sources {
+                    println!(
+                        "[{}] {} (content:{}, chunks {}-{}, rrf {:.3}, {})\n    {}",
+                        source.index,
+                        source.label(),
+                        source.content_id,
+                        source.first_chunk,
+                        source.last_chunk,
+                        source.score,
+                        source.scores,
+                        source.snippet
+                    );
+                }
+            }
+            Ok(EXIT_OK)
+        }
+        Commands::Remember { content } => {
+            if content.trim().is_empty() {
+                eprintln!("error: note is empty");
+                return Ok(EXIT_USAGE);
+            }
+            let ingested = ingest_note(vdb, &content, chunking).await?;
+            print_cache_stats(vdb);
+            print_status(&ingested);
+            println!("{}", ingested.content.id);
+            Ok(EXIT_OK)
+        }
+        Commands::Upload {
+            path,
+            include,
+            exclude,
+        } => {
+            if path.is_dir() {
+                let walk = WalkOptions { include, exclude };
+                let reports = ingest_dir(vdb, &path, &walk, chunking, print_report).await?;
+                print_cache_stats(vdb);
+                let failed = reports
+                    .iter()
+                    .filter(|r| matches!(r.outcome, FileOutcome::Failed(_)))
+                    .count();
+                let skipped = reports
+                    .iter()
+                    .filter(|r| matches!(r.outcome, FileOutcome::Skipped(_)))
+                    .count();
+                eprintln!(
+                    "{} files: {} ingested, {} skipped, {} failed",
+                    reports.len(),
+                    reports.len() - failed - skipped,
+                    skipped,
+                    failed
+                );
+                return Ok(if failed > 0 { EXIT_FAILURE } else { EXIT_OK });
+            }
+            let ingested = ingest_path(vdb, &path, chunking).await?;
+            print_cache_stats(vdb);
+            print_status(&ingested);
+            println!("{}\t{}", ingested.content.id, ingested.content.title);
+            Ok(EXIT_OK)
+        }
+        Commands::Watch {
+            path,
+            include,
+            exclude,
+        } => {
+            let walk = WalkOptions { include, exclude };
+            eprintln!("watching {}, ctrl-c to stop", path.display());
+            watch_dir(vdb, &path, &walk, chunking, print_report).await?;
+            Ok(EXIT_OK)
+        }
+        Commands::Forget { content_id, all } => {
+            if all {
+                vdb.delete_all_content().await?;
+                println!("forgot all content");
+                return Ok(EXIT_OK);
+            }
+            let Some(content_id) = content_id else {
+                eprintln!("error: pass a content id or --all");
+                return Ok(EXIT_USAGE);
+            };
+            let id = content_id
+                .strip_prefix("content:")
+                .unwrap_or(&content_id)
+                .to_string();
+            let thing = surrealdb::sql::thing(&format!("content:{}", id))?;
+            if vdb.find_content(thing).await?.is_none() {
+                eprintln!("error: no content with id {}", id);
+                return Ok(EXIT_NOT_FOUND);
+            }
+            vdb.delete_content(&id).await?;
+            println!("forgot content:{}", id);
+            Ok(EXIT_OK)
+        }
+        Commands::List { start, limit } => {
+            let contents = vdb.get_all_content(start, limit).await?;
+            for content in contents {
+                println!("{}\t{}\t{}", content.id, content.title, content.created_at);
+            }
+            Ok(EXIT_OK)
+        }
+        Commands::Reembed => {
+            let report = vdb
+                .reembed(chunking, |done, total, content| {
+                    eprintln!("[{}/{}] {}\t{}", done, total, content.id, content.title);
+                })
+                .await?;
+            print_cache_stats(vdb);
+            if report.resumed > 0 {
+                eprintln!("resumed, {} contents were already done", report.resumed);
+            }
+            println!(
+                "reembedded {} contents, now serving generation {}",
+                report.contents, report.generation
+            );
+            Ok(EXIT_OK)
+        }
+        Commands::Tui | Commands::Serve { .. } => {
+            eprintln!("error: not a one-shot command");
+            Ok(EXIT_USAGE)
+        }
+    }
+}
+
+// one line per file: status, path and the content id or why it was not ingested
+fn print_report(report: &FileReport) {
+    println!(
+        "{}\t{}\t{}",
+        report.status(),
+        report.path.display(),
+        report.detail()
+    );
+}
+
+// a re-upload keeps the content id, say what happened to it
+fn print_status(ingested: &Ingested) {
+    if ingested.status != IngestStatus::Added {
+        eprintln!("{}: {}", ingested.status.as_str(), ingested.content.id);
+    }
+}
+
+// on stderr, so stdout stays the bare result
+fn print_cache_stats(vdb: &VDB) {
+    let stats = vdb.cache_stats();
+    eprintln!(
+        "embedding cache: {} hits, {} misses",
+        stats.hits, stats.misses
+    );
+}
@@ -1,13 +1,140 @@
+pub mod commands;
 pub mod runner;
 
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf};
+
+use crate::{
+    ai::embedding::{EmbedderOptions, Pooling},
+    data::{
+        chunker::{ChunkOptions, ChunkStrategy},
+        retrieval::{RetrievalOptions, SearchMode},
+    },
+    router::session::SessionKey,
+};
 
 #[derive(Debug, Parser)] // requires `derive` feature
 #[command(name = "ragme")]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    #[command(flatten)]
+    pub retrieval: RetrievalArgs,
+    #[command(flatten)]
+    pub chunking: ChunkArgs,
+    #[command(flatten)]
+    pub embedding: EmbeddingArgs,
+}
+
+// how text is turned into vectors, must match between ingestion and questions
+#[derive(Debug, Args)]
+pub struct EmbeddingArgs {
+    // hub id of the embedding model, defaults to the one the database was built with
+    #[arg(long, global = true, env = "RAGME_EMBEDDING_MODEL")]
+    pub embedding_model: Option<String>,
+    // nfkc normalize text before embedding (full-width forms, ligatures, superscripts)
+    #[arg(long, global = true, default_value = "false")]
+    pub nfkc: bool,
+    // pooling of token embeddings, defaults to the model's sentence-transformers config
+    #[arg(long, global = true, value_enum)]
+    pub pooling: Option<Pooling>,
+    // text put before every query, overrides the model's default (e.g. "query: ")
+    #[arg(long, global = true)]
+    pub query_prefix: Option<String>,
+    // text put before every stored chunk, overrides the model's default (e.g. "passage: ")
+    #[arg(long, global = true)]
+    pub document_prefix: Option<String>,
+    // skip loading the splade model, chunks are then stored without sparse vectors
+    #[arg(long, global = true, default_value = "false")]
+    pub no_sparse: bool,
+}
+
+impl From<&EmbeddingArgs> for EmbedderOptions {
+    fn from(args: &EmbeddingArgs) -> Self {
+        EmbedderOptions {
+            nfkc: args.nfkc,
+            pooling: args.pooling,
+            query_prefix: args.query_prefix.clone(),
+            document_prefix: args.document_prefix.clone(),
+        }
+    }
+}
+
+// retrieval tuning, shared by `ask`, `tui` and `serve` (where it sets the server defaults)
+#[derive(Debug, Args)]
+pub struct RetrievalArgs {
+    // number of chunks retrieved per query, 1 to 100
+    #[arg(long, global = true, default_value = "4")]
+    pub top_k: usize,
+    // drop vector hits with a cosine similarity below this, keyword and sparse hits are kept.
+    // `--min-score` is the old name.
+    #[arg(long, global = true, alias = "min-score", default_value = "0.0")]
+    pub min_similarity: f32,
+    // chunks added before every hit
+    #[arg(long, global = true, default_value = "1")]
+    pub neighbors_before: u16,
+    // chunks added after every hit
+    #[arg(long, global = true, default_value = "1")]
+    pub neighbors_after: u16,
+    // tokens shared by the prompt and the answer, lowest scoring passages are dropped to fit
+    #[arg(long, global = true, default_value = "2048")]
+    pub context_budget: usize,
+    // how to look up related chunks, `exact` scans every vector for comparison
+    #[arg(long, global = true, value_enum, default_value_t = SearchMode::Hnsw)]
+    pub search_mode: SearchMode,
+    // weight of vector similarity in the hybrid ranking, 0 turns it off
+    #[arg(long, global = true, default_value = "1.0")]
+    pub vector_weight: f32,
+    // weight of bm25 keyword search in the hybrid ranking, 0 turns it off
+    #[arg(long, global = true, default_value = "1.0")]
+    pub keyword_weight: f32,
+    // weight of splade sparse term matching in the hybrid ranking, 0 turns it off
+    #[arg(long, global = true, default_value = "1.0")]
+    pub sparse_weight: f32,
+    // reciprocal rank fusion constant
+    #[arg(long, global = true, default_value = "60")]
+    pub rrf_k: f32,
+}
+
+impl From<&RetrievalArgs> for RetrievalOptions {
+    fn from(args: &RetrievalArgs) -> Self {
+        RetrievalOptions {
+            top_k: args.top_k,
+            min_similarity: args.min_similarity,
+            neighbors_before: args.neighbors_before,
+            neighbors_after: args.neighbors_after,
+            context_budget: args.context_budget,
+            search_mode: args.search_mode,
+            vector_weight: args.vector_weight,
+            keyword_weight: args.keyword_weight,
+            sparse_weight: args.sparse_weight,
+            rrf_k: args.rrf_k,
+        }
+    }
+}
+
+// how `remember` and `upload` cut content into chunks (also the defaults for `tui` and `serve`)
+#[derive(Debug, Args)]
+pub struct ChunkArgs {
+    // token windows, sentences or paragraphs
+    #[arg(long, global = true, value_enum, default_value_t = ChunkStrategy::Paragraphs)]
+    pub chunk_strategy: ChunkStrategy,
+    // target chunk size in embedding tokens
+    #[arg(long, global = true, default_value = "256")]
+    pub chunk_size: usize,
+    // tokens shared by consecutive chunks
+    #[arg(long, global = true, default_value = "32")]
+    pub chunk_overlap: usize,
+}
+
+impl From<&ChunkArgs> for ChunkOptions {
+    fn from(args: &ChunkArgs) -> Self {
+        ChunkOptions {
+            strategy: args.chunk_strategy,
+            max_tokens: args.chunk_size,
+            overlap: args.chunk_overlap,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)] // requires `derive` feature
@@ -15,15 +142,34 @@ pub enum Commands {
     #[command(arg_required_else_help = true)]
     Ask {
         query: Vec<String>,
+        // continue an earlier conversation, a new session is started otherwise
+        #[arg(long)]
+        session: Option<SessionKey>,
     },
     // for sentences
     Remember {
         content: String,
     },
-    // for files
+    // for files, or every supported file below a directory
     Upload {
         // add content type for ingesting data
         path: PathBuf,
+        // with a directory, only ingest files matching these globs (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
+        // with a directory, leave out files matching these globs (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+    // keep the knowledge base in sync with a directory until ctrl-c
+    Watch {
+        path: PathBuf,
+        // only ingest files matching these globs (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
+        // leave out files matching these globs (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
     },
     Forget {
         // the content to forget
@@ -41,4 +187,42 @@ pub enum Commands {
         #[arg(short, long, default_value = "10")]
         limit: u16,
     },
+    // embed all content again with the selected model and chunking, e.g. after switching
+    // models. resumes an interrupted run.
+    Reembed,
+    // interactive terminal ui
+    Tui,
+    // http api over the same knowledge base
+    Serve {
+        #[arg(short, long, default_value = "127.0.0.1:3000")]
+        addr: SocketAddr,
+    },
+}
+
+impl Commands {
+    // only asking needs the generation model, everything else can skip loading it
+    pub fn needs_inference(&self) -> bool {
+        matches!(
+            self,
+            Commands::Ask { .. } | Commands::Tui | Commands::Serve { .. }
+        )
+    }
+
+    // listing and forgetting never embed anything, the splade model is not loaded for them
+    pub fn needs_sparse(&self) -> bool {
+        !matches!(self, Commands::List { .. } | Commands::Forget { .. })
+    }
+
+    // commands that store chunks, which get sparse postings only while the splade model is loaded
+    pub fn stores_chunks(&self) -> bool {
+        matches!(
+            self,
+            Commands::Remember { .. }
+                | Commands::Upload { .. }
+                | Commands::Watch { .. }
+                | Commands::Reembed
+                | Commands::Tui
+                | Commands::Serve { .. }
+        )
+    }
 }
@@ -1,10 +1,13 @@
 use crate::{
-    ai::AI,
+    ai::{worker_pool::StreamEvent, AI},
     data::{
+        chunker::ChunkOptions,
         database::{Content, VDB},
-        ingest,
+        ingest::{ingest_dir, ingest_note, ingest_path, WalkOptions},
+        retrieval::{RetrievalOptions, SearchMode},
     },
-    qa::answer_query,
+    qa::{answer_query_stream, Source},
+    router::session::SessionKey,
     utils::get_current_working_dir,
 };
 use anyhow::Result;
@@ -17,16 +20,11 @@ use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
+    text::{Line, Span, Text},
     widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
     Terminal,
 };
-use std::{
-    collections::VecDeque,
-    error::Error,
-    io::stdout,
-    sync::Arc,
-};
+use std::{collections::VecDeque, error::Error, io::stdout, sync::Arc};
 use tokio::sync::mpsc;
 
 #[derive(Debug, Clone, Copy)]
@@ -41,6 +39,8 @@ enum Focus {
 enum AppEvent {
     Input(Event),
     Tick,
+    AnswerSources(Vec<Source>),
+    AnswerToken(String),
     Answered {
         query: String,
         result: Result<String>,
@@ -58,13 +58,18 @@ struct App {
     limit: u16,
     contents: Vec<Content>,
     answer: String,
+    sources: Vec<Source>,
     logs: VecDeque<String>,
     status: String,
+    retrieval: RetrievalOptions,
+    // how remembered notes and uploads are chunked
+    chunking: ChunkOptions,
+    session: SessionKey,
     should_quit: bool,
 }
 
 impl App {
-    fn new() -> Self {
+    fn new(retrieval: RetrievalOptions, chunking: ChunkOptions) -> Self {
         Self {
             focus: Focus::Ask,
             ask_input: String::new(),
@@ -74,8 +79,12 @@ impl App {
             limit: 10,
             contents: Vec::new(),
             answer: String::new(),
+            sources: Vec::new(),
             logs: VecDeque::new(),
             status: "Ready".into(),
+            retrieval,
+            chunking,
+            session: SessionKey::new(),
             should_quit: false,
         }
     }
@@ -107,12 +116,17 @@ impl App {
     }
 }
 
-pub async fn run_repl(vdb: Arc<VDB>, ai: Arc<AI>) -> Result<(), Box<dyn Error>> {
+pub async fn run_repl(
+    vdb: Arc<VDB>,
+    ai: Arc<AI>,
+    retrieval: RetrievalOptions,
+    chunking: ChunkOptions,
+) -> Result<(), Box<dyn Error>> {
     enable_raw_mode()?;
     execute!(stdout(), EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(stdout());
     let mut terminal = Terminal::new(backend)?;
-    let mut app = App::new();
+    let mut app = App::new(retrieval, chunking);
 
     // event bus
     let (ev_tx, mut ev_rx) = mpsc::channel::<AppEvent>(128);
@@ -158,6 +172,11 @@ pub async fn run_repl(vdb: Arc<VDB>, ai: Arc<AI>) -> Result<(), Box<dyn Error>>
                 app.contents = items;
                 app.status = "Ready".into();
             }
+            Some(AppEvent::AnswerSources(sources)) => app.sources = sources,
+            Some(AppEvent::AnswerToken(token)) => {
+                app.answer.push_str(&token);
+                app.status = "Answering…".into();
+            }
             Some(AppEvent::Answered { query, result }) => {
                 match result {
                     Ok(ans) => app.answer = ans,
@@ -192,6 +211,42 @@ async fn handle_input(
             KeyCode::Tab => app.cycle_focus(false),
             KeyCode::BackTab => app.cycle_focus(true),
 
+            KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.retrieval.search_mode = match app.retrieval.search_mode {
+                    SearchMode::Hnsw => SearchMode::Exact,
+                    SearchMode::Exact => SearchMode::Hnsw,
+                };
+                app.push_log(format!("search mode: {:?}", app.retrieval.search_mode));
+            }
+
+            KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.session = SessionKey::new();
+                app.answer.clear();
+                app.sources.clear();
+                app.push_log(format!("new session {}", app.session));
+            }
+
+            // retrieval tuning for the next question
+            KeyCode::Up if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.retrieval.top_k = app.retrieval.top_k.saturating_add(1).min(32);
+            }
+            KeyCode::Down if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.retrieval.top_k = app.retrieval.top_k.saturating_sub(1).max(1);
+            }
+            KeyCode::Right if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.retrieval.neighbors_before = app.retrieval.neighbors_before.saturating_add(1);
+                app.retrieval.neighbors_after = app.retrieval.neighbors_after.saturating_add(1);
+            }
+            KeyCode::Left if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.retrieval.neighbors_before = app.retrieval.neighbors_before.saturating_sub(1);
+                app.retrieval.neighbors_after = app.retrieval.neighbors_after.saturating_sub(1);
+            }
+            KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                // cycle the similarity threshold 0.0 -> 0.9 in steps of 0.1
+                let next = ((app.retrieval.min_similarity * 10.0).round() + 1.0) % 10.0;
+                app.retrieval.min_similarity = next / 10.0;
+            }
+
             KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
                 app.status = "Refreshing content…".into();
                 let tx = ev_tx.clone();
@@ -214,17 +269,17 @@ async fn handle_input(
                         app.status = "Query is empty".into();
                     } else {
                         app.status = "Thinking…".into();
+                        app.answer.clear();
+                        app.sources.clear();
                         let tx = ev_tx.clone();
                         let vdb = vdb.clone();
                         let ai = ai.clone();
+                        let options = app.retrieval.clone();
+                        let session = app.session.clone();
                         tokio::spawn(async move {
-                            let res = answer_query(&query, &vdb, &ai).await;
-                            let _ = tx
-                                .send(AppEvent::Answered {
-                                    query,
-                                    result: res.map(|r| r.0).map_err(|e| e.into()),
-                                })
-                                .await;
+                            let result =
+                                stream_answer(&query, &vdb, &ai, &options, &session, &tx).await;
+                            let _ = tx.send(AppEvent::Answered { query, result }).await;
                         });
                     }
                 }
@@ -236,8 +291,11 @@ async fn handle_input(
                         app.status = "Saving…".into();
                         let tx = ev_tx.clone();
                         let vdb = vdb.clone();
+                        let chunking = app.chunking.clone();
                         tokio::spawn(async move {
-                            let res = ingest_note(&vdb, &note).await;
+                            let res = ingest_note(&vdb, &note, &chunking)
+                                .await
+                                .map(|i| (i.content.id, i.status.as_str()));
                             let _ = tx.send(AppEvent::Log(format!("remember: {res:?}"))).await;
                             if res.is_ok() {
                                 let _ = tx
@@ -257,10 +315,45 @@ async fn handle_input(
                         app.status = "Uploading…".into();
                         let tx = ev_tx.clone();
                         let vdb = vdb.clone();
+                        let chunking = app.chunking.clone();
                         tokio::spawn(async move {
-                            let res = ingest_path(&vdb, &path).await;
-                            let _ = tx.send(AppEvent::Log(format!("upload: {res:?}"))).await;
-                            if res.is_ok() {
+                            let path = match get_current_working_dir() {
+                                Ok(cwd) => cwd.join(&path),
+                                Err(e) => {
+                                    let _ = tx.send(AppEvent::Log(format!("upload: {e}"))).await;
+                                    return;
+                                }
+                            };
+                            // a directory is walked, every file gets its own log line
+                            let ok = if path.is_dir() {
+                                let walk = WalkOptions::default();
+                                match ingest_dir(&vdb, &path, &walk, &chunking, |_| {}).await {
+                                    Ok(reports) => {
+                                        for r in &reports {
+                                            let line = format!(
+                                                "upload: {} {} {}",
+                                                r.status(),
+                                                r.path.display(),
+                                                r.detail()
+                                            );
+                                            let _ = tx.send(AppEvent::Log(line)).await;
+                                        }
+                                        true
+                                    }
+                                    Err(e) => {
+                                        let line = format!("upload: {e:#}");
+                                        let _ = tx.send(AppEvent::Log(line)).await;
+                                        false
+                                    }
+                                }
+                            } else {
+                                let res = ingest_path(&vdb, &path, &chunking)
+                                    .await
+                                    .map(|i| (i.content.id, i.status.as_str()));
+                                let _ = tx.send(AppEvent::Log(format!("upload: {res:?}"))).await;
+                                res.is_ok()
+                            };
+                            if ok {
                                 let _ = tx
                                     .send(AppEvent::ContentLoaded(
                                         vdb.get_all_content(0, 10).await.unwrap_or_default(),
@@ -321,33 +414,39 @@ async fn handle_input(
     Ok(())
 }
 
-async fn ingest_note(vdb: &Arc<VDB>, note: &str) -> anyhow::Result<()> {
-    vdb.process_content(
-        "note",
-        note,
-        serde_json::json!({"source": "note", "kind": "remember"}),
-    )
-    .await?;
-    Ok(())
-}
-
-async fn ingest_path(vdb: &Arc<VDB>, path_str: &str) -> anyhow::Result<()> {
-    let cwd = get_current_working_dir()?;
-    let path = cwd.join(path_str);
-    let metadata = tokio::fs::metadata(&path).await?;
-    if !metadata.is_file() {
-        panic!("Path is not a file");
+// forwards tokens to the answer pane as they are decoded, resolves to the full answer
+async fn stream_answer(
+    query: &str,
+    vdb: &Arc<VDB>,
+    ai: &Arc<AI>,
+    options: &RetrievalOptions,
+    session: &SessionKey,
+    tx: &mpsc::Sender<AppEvent>,
+) -> Result<String> {
+    let mut answer = answer_query_stream(query, vdb, ai, options, session).await?;
+    if answer.standalone_query != query {
+        let msg = format!("searched for: {}", answer.standalone_query);
+        let _ = tx.send(AppEvent::Log(msg)).await;
+    }
+    if answer.context.is_truncated() {
+        let msg = format!(
+            "context trimmed to {} tokens, {} passages dropped",
+            answer.context.budget,
+            answer.context.dropped.len()
+        );
+        let _ = tx.send(AppEvent::Log(msg)).await;
     }
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        match ext {
-            "txt" => ingest::ingest_via_txt(vdb, &path).await?,
-            "pdf" => ingest::ingest_via_pdf(vdb, &path).await?,
-            _ => panic!("Unsupported file type: {}", ext),
+    let _ = tx.send(AppEvent::AnswerSources(answer.sources)).await;
+    while let Some(event) = answer.events.recv().await {
+        match event {
+            StreamEvent::Token(token) => {
+                let _ = tx.send(AppEvent::AnswerToken(token)).await;
+            }
+            StreamEvent::Done(result) => return Ok(result.0),
+            StreamEvent::Error(e) => anyhow::bail!(e),
         }
-    } else {
-        panic!("File has no extension");
     }
-    Ok(())
+    anyhow::bail!("inference worker stopped before finishing")
 }
 
 async fn refresh_content(app: &mut App, vdb: &Arc<VDB>) -> Result<(), Box<dyn Error>> {
@@ -362,7 +461,7 @@ fn draw_ui(f: &mut ratatui::Frame, app: &App) {
         .constraints([
             Constraint::Length(3),
             Constraint::Min(5),
-            Constraint::Length(6),
+            Constraint::Length(7),
         ])
         .split(f.size());
 
@@ -394,7 +493,17 @@ fn draw_header(f: &mut ratatui::Frame, area: Rect, app: &App) {
                 .fg(Color::Cyan)
                 .add_modifier(Modifier::BOLD),
         ),
-        Span::raw(format!("status: {} | focus: {}", app.status, focus)),
+        Span::raw(format!(
+            "status: {} | focus: {} | session: {} | search: {:?} k={} ±{}/{} min sim={:.1}",
+            app.status,
+            focus,
+            app.session,
+            app.retrieval.search_mode,
+            app.retrieval.top_k,
+            app.retrieval.neighbors_before,
+            app.retrieval.neighbors_after,
+            app.retrieval.min_similarity
+        )),
     ])])
     .block(Block::default().borders(Borders::ALL).title("Status"));
     f.render_widget(header, area);
@@ -427,7 +536,25 @@ fn draw_left(f: &mut ratatui::Frame, area: Rect, app: &App) {
     ));
     f.render_widget(upload, chunks[2]);
 
-    let answer = Paragraph::new(app.answer.as_str())
+    let mut text = Text::from(app.answer.as_str());
+    if !app.sources.is_empty() {
+        text.push_line(Line::from(""));
+        text.push_line(Line::styled(
+            "Sources",
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        text.extend(app.sources.iter().map(|s| {
+            Line::from(vec![
+                Span::styled(format!("[{}] ", s.index), Style::default().fg(Color::Cyan)),
+                Span::styled(s.label(), Style::default().fg(Color::Green)),
+                Span::raw(format!(
+                    " chunks {}-{} · rrf {:.3} · {} · {}",
+                    s.first_chunk, s.last_chunk, s.score, s.scores, s.snippet
+                )),
+            ])
+        }));
+    }
+    let answer = Paragraph::new(text)
         .wrap(Wrap { trim: true })
         .block(Block::default().borders(Borders::ALL).title("Answer"));
     f.render_widget(answer, chunks[3]);
@@ -478,12 +605,15 @@ fn draw_right(f: &mut ratatui::Frame, area: Rect, app: &App) {
 fn draw_footer(f: &mut ratatui::Frame, area: Rect, app: &App) {
     let help = Paragraph::new(vec![
         Line::from(
-            "Tab/Shift-Tab: switch focus | Enter: run action | r: refresh list | q/esc: quit",
+            "Tab/Shift-Tab: switch focus | Enter: run action | Ctrl-r: refresh list | Ctrl-e: toggle exact search | q/esc: quit",
         ),
         Line::from(
             "Ask: type question -> Enter | Remember: type note -> Enter | Upload: path -> Enter",
         ),
         Line::from("List: +/- to page (start +=/-= limit) | Ready state: minimal key hints."),
+        Line::from(
+            "Ctrl-Up/Down: top k | Ctrl-Left/Right: neighbour window | Ctrl-t: min similarity | Ctrl-n: new session",
+        ),
     ])
     .block(input_block("Help", matches!(app.focus, Focus::Help)))
     .wrap(Wrap { trim: true });
@@ -0,0 +1,552 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::ai::EmbeddingEngine;
+
+// periods after these words do not end a sentence
+const ABBREVIATIONS: &[&str] = &[
+    "approx", "cf", "dr", "etc", "fig", "inc", "jr", "ltd", "mr", "mrs", "ms", "no", "prof", "sr",
+    "st", "vs",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkStrategy {
+    // fixed windows of `max_tokens`, consecutive windows share `overlap` tokens
+    Tokens,
+    // whole sentences packed up to `max_tokens`
+    Sentences,
+    // blank-line separated paragraphs merged up to `max_tokens`, long ones are split by sentence
+    #[default]
+    Paragraphs,
+}
+
+impl ChunkStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChunkStrategy::Tokens => "tokens",
+            ChunkStrategy::Sentences => "sentences",
+            ChunkStrategy::Paragraphs => "paragraphs",
+        }
+    }
+}
+
+// how a document is cut into chunks before embedding, sizes are in embedding tokens
+#[derive(Debug, Clone)]
+pub struct ChunkOptions {
+    pub strategy: ChunkStrategy,
+    // the embedder truncates its input at 512 tokens, chunks should stay well below that
+    pub max_tokens: usize,
+    // tokens repeated at the start of the next chunk so a sentence cut at the border is
+    // still found in one piece
+    pub overlap: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            strategy: ChunkStrategy::default(),
+            max_tokens: 256,
+            overlap: 32,
+        }
+    }
+}
+
+impl ChunkOptions {
+    pub fn check(&self) -> Result<()> {
+        if self.max_tokens == 0 {
+            bail!("chunk size must be at least one token");
+        }
+        if self.overlap >= self.max_tokens {
+            bail!(
+                "chunk overlap ({}) must be smaller than the chunk size ({})",
+                self.overlap,
+                self.max_tokens
+            );
+        }
+        Ok(())
+    }
+
+    // stored with every chunk so it can be traced back to how it was cut
+    pub fn to_metadata(&self) -> serde_json::Value {
+        json!({
+            "strategy": self.strategy.as_str(),
+            "max_tokens": self.max_tokens,
+            "overlap": self.overlap,
+        })
+    }
+}
+
+// a piece of a document ready to be embedded, with the metadata stored next to it
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub text: String,
+    pub metadata: serde_json::Value,
+}
+
+pub trait Chunker {
+    fn chunk(&self, text: &str) -> Result<Vec<String>>;
+}
+
+pub fn chunker_for(
+    options: &ChunkOptions,
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+) -> Box<dyn Chunker + Send + Sync> {
+    let options = options.clone();
+    match options.strategy {
+        ChunkStrategy::Tokens => Box::new(TokenWindowChunker { embedder, options }),
+        ChunkStrategy::Sentences => Box::new(SentenceChunker { embedder, options }),
+        ChunkStrategy::Paragraphs => Box::new(ParagraphChunker { embedder, options }),
+    }
+}
+
+pub struct TokenWindowChunker {
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+    options: ChunkOptions,
+}
+
+impl Chunker for TokenWindowChunker {
+    fn chunk(&self, text: &str) -> Result<Vec<String>> {
+        let offsets = self.embedder.token_offsets(text)?;
+        let step = self
+            .options
+            .max_tokens
+            .saturating_sub(self.options.overlap)
+            .max(1);
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < offsets.len() {
+            let end = (start + self.options.max_tokens).min(offsets.len());
+            let chunk = slice(text, offsets[start].0, offsets[end - 1].1).trim();
+            if !chunk.is_empty() {
+                chunks.push(chunk.to_string());
+            }
+            if end == offsets.len() {
+                break;
+            }
+            start += step;
+        }
+        Ok(chunks)
+    }
+}
+
+pub struct SentenceChunker {
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+    options: ChunkOptions,
+}
+
+impl Chunker for SentenceChunker {
+    fn chunk(&self, text: &str) -> Result<Vec<String>> {
+        let windows = TokenWindowChunker {
+            embedder: self.embedder.clone(),
+            options: self.options.clone(),
+        };
+        pack(
+            self.embedder.as_ref(),
+            split_sentences(text),
+            &self.options,
+            " ",
+            &|sentence: &str| windows.chunk(sentence),
+        )
+    }
+}
+
+pub struct ParagraphChunker {
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+    options: ChunkOptions,
+}
+
+impl Chunker for ParagraphChunker {
+    fn chunk(&self, text: &str) -> Result<Vec<String>> {
+        let sentences = SentenceChunker {
+            embedder: self.embedder.clone(),
+            options: self.options.clone(),
+        };
+        pack(
+            self.embedder.as_ref(),
+            split_paragraphs(text),
+            &self.options,
+            "\n\n",
+            &|paragraph: &str| sentences.chunk(paragraph),
+        )
+    }
+}
+
+// greedily packs consecutive units into chunks of at most `max_tokens`. the trailing units of
+// a full chunk that fit in `overlap` tokens are repeated at the start of the next one. a unit
+// too long to fit any chunk is handed to `split_long` and its pieces are kept as they are.
+pub(crate) fn pack(
+    embedder: &dyn EmbeddingEngine,
+    units: Vec<&str>,
+    options: &ChunkOptions,
+    separator: &str,
+    split_long: &dyn Fn(&str) -> Result<Vec<String>>,
+) -> Result<Vec<String>> {
+    let mut chunks = Vec::new();
+    let mut current: Vec<(&str, usize)> = Vec::new();
+    let mut tokens = 0;
+
+    for unit in units {
+        let size = embedder.token_offsets(unit)?.len();
+        if size > options.max_tokens {
+            flush(&mut chunks, &current, separator);
+            current.clear();
+            tokens = 0;
+            chunks.extend(split_long(unit)?);
+            continue;
+        }
+
+        if tokens + size > options.max_tokens && !current.is_empty() {
+            flush(&mut chunks, &current, separator);
+            let mut keep_from = current.len();
+            let mut kept = 0;
+            while keep_from > 0 && kept + current[keep_from - 1].1 <= options.overlap {
+                keep_from -= 1;
+                kept += current[keep_from].1;
+            }
+            current.drain(..keep_from);
+            tokens = kept;
+            while tokens + size > options.max_tokens && !current.is_empty() {
+                tokens -= current.remove(0).1;
+            }
+        }
+
+        current.push((unit, size));
+        tokens += size;
+    }
+    flush(&mut chunks, &current, separator);
+    Ok(chunks)
+}
+
+fn flush(chunks: &mut Vec<String>, units: &[(&str, usize)], separator: &str) {
+    if !units.is_empty() {
+        let text = units.iter().map(|(u, _)| *u).collect::<Vec<&str>>();
+        chunks.push(text.join(separator));
+    }
+}
+
+// paragraphs are separated by one or more blank lines
+pub fn split_paragraphs(text: &str) -> Vec<&str> {
+    let mut paragraphs = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            let paragraph = text[start..offset].trim();
+            if !paragraph.is_empty() {
+                paragraphs.push(paragraph);
+            }
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    let paragraph = text[start..].trim();
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph);
+    }
+    paragraphs
+}
+
+// a sentence ends at `.`, `!` or `?` followed by whitespace and a word that does not start in
+// lowercase. periods after abbreviations and initials ("e.g.", "Dr.", "J. Smith") are skipped,
+// and a paragraph break always ends a sentence.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    for paragraph in split_paragraphs(text) {
+        let mut start = 0;
+        let mut chars = paragraph.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if !matches!(c, '.' | '!' | '?') {
+                continue;
+            }
+            // closing quotes and brackets belong to the sentence they end
+            let mut end = i + c.len_utf8();
+            while let Some(&(j, next)) = chars.peek() {
+                if !matches!(next, '.' | '!' | '?' | '"' | '\'' | ')' | ']' | '”' | '’') {
+                    break;
+                }
+                end = j + next.len_utf8();
+                chars.next();
+            }
+
+            let rest = &paragraph[end..];
+            if !rest.starts_with(char::is_whitespace) {
+                continue;
+            }
+            if rest.trim_start().starts_with(char::is_lowercase) {
+                continue;
+            }
+            if c == '.' && is_abbreviation(&paragraph[start..i]) {
+                continue;
+            }
+
+            let sentence = paragraph[start..end].trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence);
+            }
+            start = end;
+        }
+        let sentence = paragraph[start..].trim();
+        if !sentence.is_empty() {
+            sentences.push(sentence);
+        }
+    }
+    sentences
+}
+
+// `before` is the text up to a period, its last word decides
+fn is_abbreviation(before: &str) -> bool {
+    let word = before
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches(['(', '[', '"', '\'']);
+    if word.is_empty() {
+        return false;
+    }
+    // initials and dotted abbreviations: "J", "e.g", "U.S"
+    let dotted = word
+        .split('.')
+        .all(|part| part.chars().count() == 1 && part.chars().all(char::is_alphabetic));
+    dotted || ABBREVIATIONS.contains(&word.to_lowercase().as_str())
+}
+
+// token offsets are byte offsets, snap them to char boundaries in case a tokenizer splits
+// inside a multi-byte char
+fn slice(text: &str, mut start: usize, mut end: usize) -> &str {
+    end = end.min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    start = start.min(end);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    &text[start..end]
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use candle_core::Tensor;
+
+    // a tokenizer stand-in: every whitespace separated word is one token
+    pub(crate) struct Words;
+
+    impl EmbeddingEngine for Words {
+        fn get_embeddings(&self, _: &str) -> Result<Tensor> {
+            bail!("only counts tokens")
+        }
+
+        fn get_embeddings_batch(&self, _: &[&str]) -> Result<Tensor> {
+            bail!("only counts tokens")
+        }
+
+        fn dimension(&self) -> usize {
+            0
+        }
+
+        fn model_id(&self) -> &str {
+            "words"
+        }
+
+        fn token_offsets(&self, text: &str) -> Result<Vec<(usize, usize)>> {
+            let mut offsets = Vec::new();
+            let mut start = None;
+            for (i, c) in text.char_indices() {
+                match (c.is_whitespace(), start) {
+                    (true, Some(s)) => {
+                        offsets.push((s, i));
+                        start = None;
+                    }
+                    (false, None) => start = Some(i),
+                    _ => {}
+                }
+            }
+            if let Some(s) = start {
+                offsets.push((s, text.len()));
+            }
+            Ok(offsets)
+        }
+    }
+
+    pub(crate) fn options(
+        strategy: ChunkStrategy,
+        max_tokens: usize,
+        overlap: usize,
+    ) -> ChunkOptions {
+        ChunkOptions {
+            strategy,
+            max_tokens,
+            overlap,
+        }
+    }
+
+    fn chunk(
+        strategy: ChunkStrategy,
+        max_tokens: usize,
+        overlap: usize,
+        text: &str,
+    ) -> Result<Vec<String>> {
+        let chunker = chunker_for(&options(strategy, max_tokens, overlap), Arc::new(Words));
+        chunker.chunk(text)
+    }
+
+    #[test]
+    fn token_windows_overlap() -> Result<()> {
+        let text = "w0 w1 w2 w3 w4 w5 w6 w7 w8 w9";
+        let chunks = chunk(ChunkStrategy::Tokens, 4, 1, text)?;
+
+        assert_eq!(chunks, ["w0 w1 w2 w3", "w3 w4 w5 w6", "w6 w7 w8 w9"]);
+        Ok(())
+    }
+
+    #[test]
+    fn token_windows_of_short_text() -> Result<()> {
+        assert_eq!(chunk(ChunkStrategy::Tokens, 4, 1, "a b")?, ["a b"]);
+        assert!(chunk(ChunkStrategy::Tokens, 4, 1, "  ")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn packed_sentences_carry_over_the_overlap() -> Result<()> {
+        let text = "One two. Three four. Five six. Seven eight.";
+        let chunks = chunk(ChunkStrategy::Sentences, 4, 2, text)?;
+
+        assert_eq!(
+            chunks,
+            [
+                "One two. Three four.",
+                "Three four. Five six.",
+                "Five six. Seven eight.",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overlap_only_repeats_whole_units() -> Result<()> {
+        // the last sentence is 3 tokens, more than the overlap of 2
+        let text = "One two. Three four five. Six seven.";
+        let chunks = chunk(ChunkStrategy::Sentences, 5, 2, text)?;
+
+        assert_eq!(chunks, ["One two. Three four five.", "Six seven."]);
+        Ok(())
+    }
+
+    #[test]
+    fn unit_larger_than_max_tokens_is_split() -> Result<()> {
+        let text = "Short one. This sentence has far too many words. End here.";
+        let chunks = chunk(ChunkStrategy::Sentences, 4, 0, text)?;
+
+        assert_eq!(
+            chunks,
+            [
+                "Short one.",
+                "This sentence has far",
+                "too many words.",
+                "End here.",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn paragraphs_are_merged_up_to_the_chunk_size() -> Result<()> {
+        let text = "Para one.\n\nPara two.\n\n\nPara three is longer.";
+        let chunks = chunk(ChunkStrategy::Paragraphs, 4, 0, text)?;
+
+        assert_eq!(chunks, ["Para one.\n\nPara two.", "Para three is longer."]);
+        Ok(())
+    }
+
+    #[test]
+    fn sentences_end_at_terminal_punctuation() {
+        let sentences = split_sentences("It works! Does it? Yes. He said \"stop.\" Then left.");
+
+        assert_eq!(
+            sentences,
+            [
+                "It works!",
+                "Does it?",
+                "Yes.",
+                "He said \"stop.\"",
+                "Then left."
+            ]
+        );
+    }
+
+    #[test]
+    fn abbreviations_do_not_end_sentences() {
+        assert_eq!(
+            split_sentences("Dr. Smith arrived. He left."),
+            ["Dr. Smith arrived.", "He left."]
+        );
+        assert_eq!(
+            split_sentences("Use a tool, e.g. foo or bar. Then stop."),
+            ["Use a tool, e.g. foo or bar.", "Then stop."]
+        );
+        assert_eq!(
+            split_sentences("See the notes (e.g. Foo) first. Then stop."),
+            ["See the notes (e.g. Foo) first.", "Then stop."]
+        );
+    }
+
+    #[test]
+    fn initials_do_not_end_sentences() {
+        assert_eq!(
+            split_sentences("J. R. Smith wrote it in the U.S. Army. Nobody read it."),
+            ["J. R. Smith wrote it in the U.S. Army.", "Nobody read it."]
+        );
+    }
+
+    #[test]
+    fn paragraph_breaks_end_sentences() {
+        assert_eq!(
+            split_sentences("no period here\n\nNext paragraph"),
+            ["no period here", "Next paragraph"]
+        );
+    }
+
+    #[test]
+    fn abbreviation_check_looks_at_the_last_word() {
+        assert!(is_abbreviation("Ask Dr"));
+        assert!(is_abbreviation("see (e.g"));
+        assert!(is_abbreviation("the U.S"));
+        assert!(is_abbreviation("by J"));
+        assert!(!is_abbreviation("the end"));
+        assert!(!is_abbreviation("the e.gg"));
+        assert!(!is_abbreviation(""));
+    }
+
+    #[test]
+    fn multi_byte_text_is_cut_on_char_boundaries() -> Result<()> {
+        let text = "日本 語 テキスト 🎉 終わり";
+        let chunks = chunk(ChunkStrategy::Tokens, 2, 0, text)?;
+        assert_eq!(chunks, ["日本 語", "テキスト 🎉", "終わり"]);
+
+        let sentences = split_sentences("Ünïcödé wörks. Ça va? Oui.");
+        assert_eq!(sentences, ["Ünïcödé wörks.", "Ça va?", "Oui."]);
+        Ok(())
+    }
+
+    #[test]
+    fn slice_snaps_to_char_boundaries() {
+        // "é" spans bytes 1..3
+        let text = "héllo";
+        assert_eq!(slice(text, 2, 4), "él");
+        assert_eq!(slice(text, 0, 2), "hé");
+        assert_eq!(slice(text, 3, 99), "llo");
+    }
+
+    #[test]
+    fn options_reject_overlap_of_a_whole_chunk() {
+        assert!(options(ChunkStrategy::Tokens, 4, 4).check().is_err());
+        assert!(options(ChunkStrategy::Tokens, 0, 0).check().is_err());
+        assert!(options(ChunkStrategy::Tokens, 4, 3).check().is_ok());
+    }
+}
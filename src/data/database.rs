@@ -1,7 +1,23 @@
-use crate::ai::EmbeddingEngine;
-use anyhow::{Context, Error};
+use crate::{
+    ai::{
+        cache::{CacheStats, EmbeddingCache},
+        embedding::Pooling,
+        registry::LEGACY_MODEL,
+        sparse::SparseVector,
+        EmbeddingEngine, SparseEmbeddingEngine,
+    },
+    data::{
+        chunker::{chunker_for, Chunk, ChunkOptions},
+        markdown::chunk_markdown,
+        retrieval::{RetrievalOptions, SearchMode},
+    },
+    router::session::SessionKey,
+};
+use anyhow::{bail, Context, Error};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 use surrealdb::{
     engine::local::{Db, RocksDb},
     sql::{thing, Thing},
@@ -14,6 +30,49 @@ pub struct Content {
     pub title: String,
     pub text: String,
     pub created_at: Datetime,
+    // the file the content was read from (a path, or the file name of an http upload). a new
+    // upload of the same source updates this content instead of adding another.
+    #[serde(default)]
+    pub source: Option<String>,
+    // sha256 of `text`, an upload with the same hash is a no-op
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    // modification time of the source file when it was read, ms since the unix epoch
+    #[serde(default)]
+    pub mtime_ms: Option<u64>,
+    // the directory a `watch` ingested it from. only that watch deletes it when the file is gone.
+    #[serde(default)]
+    pub watch_root: Option<String>,
+    // outbound links of an html page, stored once here rather than with every chunk
+    #[serde(default)]
+    pub links: Option<Vec<String>>,
+}
+
+// what storing a document did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestStatus {
+    Added,
+    // same source with different text, its chunks were replaced
+    Updated,
+    // the exact text was already stored
+    Unchanged,
+}
+
+impl IngestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IngestStatus::Added => "added",
+            IngestStatus::Updated => "updated",
+            IngestStatus::Unchanged => "unchanged",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ingested {
+    pub content: Content,
+    pub status: IngestStatus,
 }
 
 #[derive(Serialize, Debug, Clone, Deserialize)]
@@ -21,34 +80,244 @@ pub struct VectorIndex {
     pub id: Thing,
     pub content_id: Thing,
     pub content_chunk: String,
-    pub chunk_number: u16,
+    pub chunk_number: u32,
     pub vector: Vec<f32>,
     pub metadata: serde_json::Value,
     pub created_at: Datetime,
+    // only set on rows returned by a similarity search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+}
+
+// one question and answer of a chat session
+#[derive(Serialize, Debug, Clone, Deserialize)]
+pub struct Turn {
+    pub id: Thing,
+    pub session_id: SessionKey,
+    pub question: String,
+    // the question rewritten to stand on its own, this is what retrieval searched for
+    pub standalone_query: String,
+    pub answer: String,
+    pub created_at: Datetime,
+}
+
+// one term of a chunk's sparse vector. postings are looked up by term, so a sparse query only
+// touches the chunks sharing at least one term with it.
+#[derive(Serialize, Debug, Clone, Deserialize)]
+pub struct SparsePosting {
+    pub chunk: Thing,
+    pub content_id: Thing,
+    pub term: u32,
+    pub weight: f32,
+}
+
+// what the vectors in this database were made with, stored as `meta:embedding`
+#[derive(Serialize, Debug, Clone, Deserialize)]
+pub struct EmbeddingMeta {
+    pub dimension: usize,
+    // hub id of the embedding model, missing in databases created before it was recorded
+    #[serde(default)]
+    pub model: Option<String>,
+    // which tables hold the live vectors, see `Tables`
+    #[serde(default)]
+    pub generation: u32,
+    // prefixes the model was given, missing in databases created before they were recorded
+    #[serde(default)]
+    pub query_prefix: Option<String>,
+    #[serde(default)]
+    pub document_prefix: Option<String>,
+    // how the token embeddings were pooled and whether text was nfkc normalized, missing in
+    // databases created before they were recorded
+    #[serde(default)]
+    pub pooling: Option<Pooling>,
+    #[serde(default)]
+    pub nfkc: Option<bool>,
+}
+
+// an unfinished `reembed`, stored as `meta:reembed` so an interrupted run picks up where it
+// stopped
+#[derive(Serialize, Debug, Clone, Deserialize)]
+pub struct ReembedState {
+    pub generation: u32,
+    pub model: String,
+    pub dimension: usize,
+    // a run with other prefixes, pooling or normalization cannot be resumed either
+    #[serde(default)]
+    pub query_prefix: String,
+    #[serde(default)]
+    pub document_prefix: String,
+    #[serde(default)]
+    pub pooling: Option<Pooling>,
+    #[serde(default)]
+    pub nfkc: bool,
+    // content already embedded into the new generation. runs recorded before the hash was kept
+    // start over.
+    #[serde(default)]
+    pub embedded: Vec<Reembedded>,
+}
+
+// one content `reembed` finished, with the hash of the text it embedded. a content updated in
+// place since then is embedded again on resume.
+#[derive(Serialize, Debug, Clone, Deserialize)]
+pub struct Reembedded {
+    pub id: Thing,
+    pub content_hash: Option<String>,
+}
+
+// what a finished `reembed` did
+#[derive(Debug, Clone)]
+pub struct ReembedReport {
+    pub generation: u32,
+    pub contents: usize,
+    // contents embedded by an earlier, interrupted run
+    pub resumed: usize,
+}
+
+// the tables one generation of vectors lives in. `reembed` fills the next generation while
+// the live one keeps serving and switches over by updating `meta:embedding`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tables {
+    pub chunks: String,
+    pub postings: String,
+}
+
+impl Tables {
+    pub fn generation(generation: u32) -> Self {
+        // generation 0 keeps the table names of databases created before re-embedding existed
+        if generation == 0 {
+            Self {
+                chunks: "vector_index".to_string(),
+                postings: "sparse_posting".to_string(),
+            }
+        } else {
+            Self {
+                chunks: format!("vector_index_{generation}"),
+                postings: format!("sparse_posting_{generation}"),
+            }
+        }
+    }
 }
 
+// where the rocksdb files live, relative to the working directory
+pub const DATABASE_PATH: &str = "./ragme.db";
+
+// chunks embedded per forward pass during ingestion
+const EMBEDDING_BATCH_SIZE: usize = 32;
+// candidate list size while walking the hnsw graph, higher is more accurate and slower
+const HNSW_EF: usize = 40;
+
 pub struct VDB {
     db: Surreal<Db>,
     embedder: Arc<dyn EmbeddingEngine + Send + Sync + 'static>,
+    // length of every vector in the store
+    dimension: usize,
+    // splade model for term-weight vectors, chunks get no sparse postings without one
+    sparse: Option<Arc<dyn SparseEmbeddingEngine + Send + Sync + 'static>>,
+    // the live generation of chunk and posting tables
+    tables: Tables,
+    // every vector the embedder produces goes through here
+    cache: EmbeddingCache,
+}
+
+// opens ./ragme.db. the handle is cheap to clone, so the embedding model can be picked from
+// what the database recorded before the `VDB` is built.
+pub async fn connect() -> anyhow::Result<Surreal<Db>> {
+    let db = Surreal::new::<RocksDb>(DATABASE_PATH)
+        .await
+        .context("Unable to connect to DB")?;
+
+    db.use_ns("rag-me")
+        .use_db("documents")
+        .await
+        .context("Failed to switch to namespace and database")?;
+    Ok(db)
+}
+
+// the embedding model the stored vectors were made with, if the database recorded one.
+// vectors stored before the model was recorded were all made with `LEGACY_MODEL`.
+pub async fn stored_embedding_model(db: &Surreal<Db>) -> anyhow::Result<Option<String>> {
+    let recorded: Option<EmbeddingMeta> = db
+        .select(("meta", "embedding"))
+        .await
+        .context("Unable to read embedding meta")?;
+    if let Some(model) = recorded.and_then(|meta| meta.model) {
+        return Ok(Some(model));
+    }
+    let mut result = db
+        .query("SELECT VALUE id FROM vector_index LIMIT 1")
+        .await
+        .context("Unable to look for stored vectors")?;
+    let stored: Option<Thing> = result.take(0)?;
+    Ok(stored.map(|_| LEGACY_MODEL.to_string()))
 }
 
 impl VDB {
     pub async fn new(
+        db: Surreal<Db>,
         embedder: Arc<dyn EmbeddingEngine + Send + Sync + 'static>,
+        sparse: Option<Arc<dyn SparseEmbeddingEngine + Send + Sync + 'static>>,
     ) -> anyhow::Result<Self> {
-        let db = Surreal::new::<RocksDb>("./ragme.db")
-            .await
-            .context("Unable to connect to DB")?;
+        let dimension = embedder.dimension();
+        let generation = check_embedding_meta(&db, embedder.as_ref()).await?;
+        let tables = Tables::generation(generation);
+        define_indexes(&db, &tables, dimension).await?;
+        let cache = EmbeddingCache::new(db.clone(), embedder.clone());
+        cache.evict().await?;
+
+        Ok(Self {
+            cache,
+            db,
+            embedder,
+            dimension,
+            sparse,
+            tables,
+        })
+    }
 
-        db.use_ns("rag-me")
-            .use_db("documents")
+    // opens the database for `reembed`, which may use a different model than the stored
+    // vectors. only `reembed` should be called on the result.
+    pub async fn for_reembed(
+        db: Surreal<Db>,
+        embedder: Arc<dyn EmbeddingEngine + Send + Sync + 'static>,
+        sparse: Option<Arc<dyn SparseEmbeddingEngine + Send + Sync + 'static>>,
+    ) -> anyhow::Result<Self> {
+        let live: Option<EmbeddingMeta> = db
+            .select(("meta", "embedding"))
             .await
-            .context("Failed to switch to namespace and database")?;
+            .context("Unable to read embedding meta")?;
+        let tables = Tables::generation(live.map(|meta| meta.generation).unwrap_or_default());
+
+        Ok(Self {
+            cache: EmbeddingCache::new(db.clone(), embedder.clone()),
+            dimension: embedder.dimension(),
+            db,
+            embedder,
+            sparse,
+            tables,
+        })
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
 
-        Ok(Self { db, embedder })
+    // embeds a search query, or takes it from the embedding cache
+    pub async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Error> {
+        self.cache.embed_query(query).await
     }
 
-    pub async fn insert_content(&self, title: &str, text: &str) -> Result<Content, Error> {
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    // stored without a hash, `process_chunks` sets it once the chunks are in. content left
+    // half-written by a crash never looks unchanged to the next upload, it is replaced.
+    pub async fn insert_content(
+        &self,
+        title: &str,
+        text: &str,
+        source: Option<&str>,
+    ) -> Result<Content, Error> {
         let id = Uuid::new_v4().to_string().replace("-", "");
         let id = thing(format!("content:{}", id).as_str())?;
         let content: Content = self
@@ -59,114 +328,420 @@ impl VDB {
                 title: title.to_string(),
                 text: text.to_string(),
                 created_at: Datetime::default(),
+                source: source.map(str::to_string),
+                content_hash: None,
+                mtime_ms: None,
+                watch_root: None,
+                links: None,
             })
             .await?
             .context("Unable to insert content")?;
         Ok(content)
     }
 
-    pub async fn insert_into_vdb(
+    // vector -> key -> content
+    pub async fn process_content(
         &self,
-        content_id: Thing,
-        chunk_number: u16,
-        content_chunk: &str,
+        title: &str,
+        source: Option<&str>,
+        text: &str,
         metadata: serde_json::Value,
-    ) -> anyhow::Result<VectorIndex, Error> {
-        let id = Uuid::new_v4().to_string().replace("-", "");
-        let id = thing(format!("vector_index:{}", id).as_str())?;
-        let content_chunk = content_chunk
-            .chars()
-            .filter(|c| c.is_ascii())
-            .collect::<String>();
+        chunking: &ChunkOptions,
+    ) -> anyhow::Result<Ingested, Error> {
+        chunking.check()?;
+        let chunks = self.chunk_text(text, metadata, chunking)?;
+        self.process_chunks(title, source, text, chunks).await
+    }
 
-        let content_chunk = content_chunk.trim();
+    // cuts plain text into chunks measured in embedding tokens
+    fn chunk_text(
+        &self,
+        text: &str,
+        metadata: serde_json::Value,
+        chunking: &ChunkOptions,
+    ) -> anyhow::Result<Vec<Chunk>, Error> {
+        let pieces = chunker_for(chunking, self.embedder.clone()).chunk(text)?;
 
-        if content_chunk.is_empty() {
-            return Err(anyhow::anyhow!("content chunk is empty!"));
+        // every chunk records how it was cut
+        let mut metadata = metadata;
+        if let Some(fields) = metadata.as_object_mut() {
+            fields.insert("chunker".to_string(), chunking.to_metadata());
         }
 
-        let vector = self
-            .embedder
-            .get_embeddings(&content_chunk)?
-            .reshape((384,))? // apparently 384 is the optimal for vector embeddings? idk.
-            .to_vec1()?;
-
-        let vector_index: VectorIndex = self
-            .db
-            .create("vector_index")
-            .content(VectorIndex {
-                id: id.clone(),
-                content_id,
-                chunk_number,
-                content_chunk: content_chunk.to_string(),
-                metadata,
-                vector,
-                created_at: Datetime::default(),
+        Ok(pieces
+            .into_iter()
+            .map(|text| Chunk {
+                text,
+                metadata: metadata.clone(),
             })
-            .await?
-            .context("unable to insert vector index")?;
-
-        Ok(vector_index)
+            .collect())
     }
 
-    // vector -> key -> content
-    pub async fn process_content(
+    // markdown is cut along its headings, every chunk knows the section it came from
+    pub async fn process_markdown(
         &self,
         title: &str,
+        source: Option<&str>,
         text: &str,
         metadata: serde_json::Value,
-    ) -> anyhow::Result<Content, Error> {
-        // insert into content
-        let content = self.insert_content(title, text).await?;
-
-        // parse the chunks, split into array of strings and remove empty.
-        let mut chunks = text.split("\n").collect::<Vec<&str>>();
-        chunks.retain(|c| !c.is_empty());
-
-        // recursively split the chunks into smaller chunks if the length is more than 1000.
-        let mut index = 0;
-        while index < chunks.len() {
-            if chunks[index].len() > 1000 {
-                let split_chunks = chunks[index]
-                    .split(".")
-                    .map(|c| c.trim())
-                    .filter(|c| !c.is_empty())
-                    .collect::<Vec<&str>>();
-
-                chunks.remove(index);
-                chunks.splice(index..index, split_chunks);
-            } else {
-                index += 1;
+        chunking: &ChunkOptions,
+    ) -> anyhow::Result<Ingested, Error> {
+        chunking.check()?;
+        let chunks = chunk_markdown(text, &metadata, chunking, self.embedder.clone())?;
+        self.process_chunks(title, source, text, chunks).await
+    }
+
+    // stores the content and embeds its chunks in order, a content whose chunks fail to
+    // embed is removed again. a document is stored once per source: an identical upload is
+    // left alone and a changed one replaces the chunks of the stored content. notes have no
+    // source, the same note is stored once.
+    pub async fn process_chunks(
+        &self,
+        title: &str,
+        source: Option<&str>,
+        text: &str,
+        chunks: Vec<Chunk>,
+    ) -> anyhow::Result<Ingested, Error> {
+        let hash = content_hash(text);
+        let existing = match source {
+            Some(source) => self.find_content_by_source(source).await?,
+            None => self.find_note_by_hash(&hash).await?,
+        };
+
+        match existing {
+            Some(content) if content.content_hash.as_deref() == Some(hash.as_str()) => {
+                Ok(Ingested {
+                    content,
+                    status: IngestStatus::Unchanged,
+                })
+            }
+            Some(content) => {
+                let content = self
+                    .replace_content(&content.id, title, text, chunks)
+                    .await?;
+                Ok(Ingested {
+                    content,
+                    status: IngestStatus::Updated,
+                })
+            }
+            None => {
+                let content = self.insert_content(title, text, source).await?;
+                if let Err(e) = self.insert_chunks(&self.tables, &content.id, chunks).await {
+                    let _ = self.delete_content(&content.id.id.to_raw()).await;
+                    return Err(e);
+                }
+                let mut result = self
+                    .db
+                    .query("UPDATE $id SET content_hash = $hash RETURN AFTER")
+                    .bind(("id", content.id.clone()))
+                    .bind(("hash", hash))
+                    .await?;
+                let content: Option<Content> = result.take(0)?;
+                let content = content.context("Unable to store content hash")?;
+                Ok(Ingested {
+                    content,
+                    status: IngestStatus::Added,
+                })
             }
         }
+    }
+
+    pub async fn set_mtime(&self, id: &Thing, mtime_ms: u64) -> Result<(), Error> {
+        self.db
+            .query("UPDATE $id SET mtime_ms = $mtime")
+            .bind(("id", id.clone()))
+            .bind(("mtime", mtime_ms))
+            .await?
+            .check()
+            .context("Unable to store modification time")?;
+        Ok(())
+    }
+
+    pub async fn set_links(&self, id: &Thing, links: &[String]) -> Result<(), Error> {
+        self.db
+            .query("UPDATE $id SET links = $links")
+            .bind(("id", id.clone()))
+            .bind(("links", links.to_vec()))
+            .await?
+            .check()
+            .context("Unable to store links")?;
+        Ok(())
+    }
+
+    pub async fn set_watch_root(&self, id: &Thing, root: &str) -> Result<(), Error> {
+        self.db
+            .query("UPDATE $id SET watch_root = $root")
+            .bind(("id", id.clone()))
+            .bind(("root", root.to_string()))
+            .await?
+            .check()
+            .context("Unable to store watched directory")?;
+        Ok(())
+    }
+
+    // contents read from files below the directory `prefix`, which ends in a separator
+    pub async fn get_contents_under(&self, prefix: &str) -> Result<Vec<Content>, Error> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM content WHERE string::starts_with(source ?? '', $prefix)")
+            .bind(("prefix", prefix.to_string()))
+            .await?;
+        let contents: Vec<Content> = result.take(0)?;
+        Ok(contents)
+    }
+
+    pub async fn find_content_by_source(&self, source: &str) -> Result<Option<Content>, Error> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM content WHERE source = $source LIMIT 1")
+            .bind(("source", source.to_string()))
+            .await?;
+        let content: Option<Content> = result.take(0)?;
+        Ok(content)
+    }
 
-        for (i, chunk) in chunks.clone().into_iter().enumerate() {
-            let res = self
-                .insert_into_vdb(content.id.clone(), i as u16, chunk, metadata.clone())
-                .await;
-            match res {
-                Ok(_) => {}
-                Err(e) => {
-                    if e.to_string().contains("content chunk is empty!") {
-                        continue;
-                    }
+    async fn find_note_by_hash(&self, hash: &str) -> Result<Option<Content>, Error> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM content WHERE source = NONE AND content_hash = $hash LIMIT 1")
+            .bind(("hash", hash.to_string()))
+            .await?;
+        let content: Option<Content> = result.take(0)?;
+        Ok(content)
+    }
+
+    // the new chunks are stored before the old ones are removed, if embedding fails the
+    // content keeps its previous version
+    async fn replace_content(
+        &self,
+        id: &Thing,
+        title: &str,
+        text: &str,
+        chunks: Vec<Chunk>,
+    ) -> Result<Content, Error> {
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT VALUE id FROM {} WHERE content_id = $id",
+                self.tables.chunks
+            ))
+            .bind(("id", id.clone()))
+            .await?;
+        let previous: Vec<Thing> = result.take(0)?;
+
+        let inserted = self.insert_chunks(&self.tables, id, chunks).await;
+        // on success the previous chunks go, on failure whatever was stored of the new version
+        let stale = if inserted.is_ok() { "IN" } else { "NOT IN" };
+        self.db
+            .query(format!(
+                "DELETE FROM {} WHERE content_id = $id AND chunk {stale} $previous; \
+                 DELETE FROM {} WHERE content_id = $id AND id {stale} $previous",
+                self.tables.postings, self.tables.chunks
+            ))
+            .bind(("id", id.clone()))
+            .bind(("previous", previous))
+            .await?
+            .check()
+            .context("Unable to remove replaced chunks")?;
+        inserted?;
+
+        let mut result = self
+            .db
+            .query("UPDATE $id SET title = $title, text = $text, content_hash = $hash RETURN AFTER")
+            .bind(("id", id.clone()))
+            .bind(("title", title.to_string()))
+            .bind(("text", text.to_string()))
+            .bind(("hash", content_hash(text)))
+            .await?;
+        let content: Option<Content> = result.take(0)?;
+        content.context("Unable to update content")
+    }
+
+    // embeds `EMBEDDING_BATCH_SIZE` chunks per forward pass and inserts each batch at once
+    async fn insert_chunks(
+        &self,
+        tables: &Tables,
+        content_id: &Thing,
+        chunks: Vec<Chunk>,
+    ) -> Result<(), Error> {
+        let mut chunks = chunks;
+        chunks.retain(|c| !c.text.trim().is_empty());
+
+        let mut chunk_number: u32 = 0;
+        for batch in chunks.chunks(EMBEDDING_BATCH_SIZE) {
+            let texts = batch.iter().map(|c| c.text.trim()).collect::<Vec<&str>>();
+            let vectors = self.cache.embed_documents(&texts).await?;
+
+            let mut rows = Vec::with_capacity(batch.len());
+            for ((chunk, text), vector) in batch.iter().zip(&texts).zip(vectors) {
+                self.check_dimension(&vector)?;
+                let id = Uuid::new_v4().to_string().replace("-", "");
+                rows.push(VectorIndex {
+                    id: thing(format!("{}:{}", tables.chunks, id).as_str())?,
+                    content_id: content_id.clone(),
+                    chunk_number,
+                    content_chunk: text.to_string(),
+                    metadata: chunk.metadata.clone(),
+                    vector,
+                    created_at: Datetime::default(),
+                    score: None,
+                });
+                chunk_number = chunk_number
+                    .checked_add(1)
+                    .context("document has too many chunks to number")?;
+            }
+
+            let ids = rows.iter().map(|r| r.id.clone()).collect::<Vec<Thing>>();
+            let _: Vec<VectorIndex> = self
+                .db
+                .insert(tables.chunks.as_str())
+                .content(rows)
+                .await
+                .context("unable to insert vector indexes")?;
+
+            if let Some(sparse) = &self.sparse {
+                let weights = sparse.get_sparse_embeddings_batch(&texts)?;
+                for (id, weights) in ids.iter().zip(weights) {
+                    self.insert_postings(tables, id, content_id, weights)
+                        .await?;
                 }
             }
         }
+        Ok(())
+    }
 
-        Ok(content)
+    async fn insert_postings(
+        &self,
+        tables: &Tables,
+        chunk: &Thing,
+        content_id: &Thing,
+        weights: SparseVector,
+    ) -> Result<(), Error> {
+        let postings = weights
+            .into_iter()
+            .map(|(term, weight)| SparsePosting {
+                chunk: chunk.clone(),
+                content_id: content_id.clone(),
+                term,
+                weight,
+            })
+            .collect::<Vec<SparsePosting>>();
+        if postings.is_empty() {
+            return Ok(());
+        }
+        let _: Vec<SparsePosting> = self
+            .db
+            .insert(tables.postings.as_str())
+            .content(postings)
+            .await
+            .context("unable to insert sparse postings")?;
+        Ok(())
     }
 
     // using cosine similarity to find nearby vectors
-    pub async fn get_related_chunks(&self, query: Vec<f32>) -> Result<Vec<VectorIndex>, Error> {
-        let mut result = self.db
-            .query("SELECT *, vector::similarity::cosine(vector, $query) AS score FROM vector_index ORDER BY score DESC LIMIT 4")
-            .bind(("query", query))
+    pub async fn get_related_chunks(
+        &self,
+        query: Vec<f32>,
+        options: &RetrievalOptions,
+    ) -> Result<Vec<VectorIndex>, Error> {
+        self.check_dimension(&query)?;
+        let top_k = options.top_k.max(1);
+        let sql = match options.search_mode {
+            // the knn operator walks the hnsw index, the score is only computed for its hits
+            SearchMode::Hnsw => format!(
+                "SELECT *, vector::similarity::cosine(vector, $query) AS score FROM {} \
+                 WHERE vector <|{},{}|> $query ORDER BY score DESC",
+                self.tables.chunks,
+                top_k,
+                HNSW_EF.max(top_k)
+            ),
+            SearchMode::Exact => format!(
+                "SELECT *, vector::similarity::cosine(vector, $query) AS score FROM {} \
+                 ORDER BY score DESC LIMIT {}",
+                self.tables.chunks, top_k
+            ),
+        };
+        let mut result = self.db.query(sql).bind(("query", query)).await?;
+        let mut vector_indexes: Vec<VectorIndex> = result.take(0)?;
+        vector_indexes.retain(|v| v.score.unwrap_or_default() >= options.min_similarity);
+        Ok(vector_indexes)
+    }
+
+    // bm25 ranked full-text search over the chunk text
+    pub async fn get_keyword_chunks(
+        &self,
+        query: &str,
+        options: &RetrievalOptions,
+    ) -> Result<Vec<VectorIndex>, Error> {
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT *, search::score(1) AS score FROM {} \
+                 WHERE content_chunk @1@ $query ORDER BY score DESC LIMIT $limit",
+                self.tables.chunks
+            ))
+            .bind(("query", query.to_string()))
+            .bind(("limit", options.top_k))
             .await?;
         let vector_indexes = result.take(0)?;
         Ok(vector_indexes)
     }
 
+    // splade retrieval: the dot product of the query's and each chunk's term weights, summed
+    // over the postings of the query terms. empty when no sparse model is loaded.
+    pub async fn get_sparse_chunks(
+        &self,
+        query: &str,
+        options: &RetrievalOptions,
+    ) -> Result<Vec<VectorIndex>, Error> {
+        let Some(sparse) = &self.sparse else {
+            return Ok(Vec::new());
+        };
+        let query = sparse.get_sparse_embedding(query)?;
+        let terms = query.keys().copied().collect::<Vec<u32>>();
+
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT * FROM {} WHERE term IN $terms",
+                self.tables.postings
+            ))
+            .bind(("terms", terms))
+            .await?;
+        let postings: Vec<SparsePosting> = result.take(0)?;
+
+        let mut scores: HashMap<String, (Thing, f32)> = HashMap::new();
+        for posting in postings {
+            let weight = query.get(&posting.term).copied().unwrap_or_default();
+            scores
+                .entry(posting.chunk.to_string())
+                .or_insert((posting.chunk, 0.0))
+                .1 += weight * posting.weight;
+        }
+        let mut scores = scores.into_values().collect::<Vec<(Thing, f32)>>();
+        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        scores.truncate(options.top_k.max(1));
+
+        let ids = scores
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<Thing>>();
+        let mut result = self
+            .db
+            .query("SELECT * FROM $ids")
+            .bind(("ids", ids))
+            .await?;
+        let mut chunks: Vec<VectorIndex> = result.take(0)?;
+        for chunk in chunks.iter_mut() {
+            chunk.score = scores
+                .iter()
+                .find(|(id, _)| *id == chunk.id)
+                .map(|(_, s)| *s);
+        }
+        chunks.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        Ok(chunks)
+    }
+
     pub async fn get_all_content(&self, start: u16, limit: u16) -> Result<Vec<Content>, Error> {
         let mut result = self
             .db
@@ -183,40 +758,119 @@ impl VDB {
     pub async fn delete_content(&self, id: &str) -> Result<(), Error> {
         let id = thing(format!("content:{}", id).as_str())?;
 
-        let _ = self
-            .db
-            .query("DELETE FROM vector_index WHERE content_id = $id")
-            .bind(("id", id.clone()))
+        // all or nothing, vectors without their content would still come back as hits
+        self.db
+            .query(format!(
+                "BEGIN TRANSACTION; \
+                 DELETE FROM {} WHERE content_id = $id; \
+                 DELETE FROM {} WHERE content_id = $id; \
+                 DELETE FROM content WHERE id = $id; \
+                 COMMIT TRANSACTION;",
+                self.tables.postings, self.tables.chunks
+            ))
+            .bind(("id", id))
             .await?
             .check()
-            .context("unable to delete content");
+            .context("Unable to delete content")?;
 
-        let _ = self
-            .db
-            .query("DELETE FROM content WHERE id = $id")
-            .bind(("id", id.clone()))
+        Ok(())
+    }
+
+    // wipe every content and its vector index
+    pub async fn delete_all_content(&self) -> Result<(), Error> {
+        self.db
+            .query(format!(
+                "DELETE FROM {}; DELETE FROM {}; DELETE FROM content;",
+                self.tables.postings, self.tables.chunks
+            ))
             .await?
             .check()
-            .context("Unable to delete content")?;
+            .context("Unable to delete all content")?;
+        // nothing is left to re-upload, the vectors would only take up space
+        self.cache.clear().await?;
 
         Ok(())
     }
 
+    pub async fn insert_turn(
+        &self,
+        session_id: &SessionKey,
+        question: &str,
+        standalone_query: &str,
+        answer: &str,
+    ) -> Result<Turn, Error> {
+        let id = Uuid::new_v4().to_string().replace("-", "");
+        let id = thing(format!("turn:{}", id).as_str())?;
+        let turn: Turn = self
+            .db
+            .create("turn")
+            .content(Turn {
+                id: id.clone(),
+                session_id: session_id.clone(),
+                question: question.to_string(),
+                standalone_query: standalone_query.to_string(),
+                answer: answer.to_string(),
+                created_at: Datetime::default(),
+            })
+            .await?
+            .context("Unable to insert turn")?;
+        Ok(turn)
+    }
+
+    // the last `limit` turns of a session, oldest first
+    pub async fn get_turns(
+        &self,
+        session_id: &SessionKey,
+        limit: usize,
+    ) -> Result<Vec<Turn>, Error> {
+        let mut result = self
+            .db
+            .query("SELECT * FROM turn WHERE session_id = $session ORDER BY created_at DESC LIMIT $limit")
+            .bind(("session", session_id.clone()))
+            .bind(("limit", limit))
+            .await?;
+        let mut turns: Vec<Turn> = result.take(0)?;
+        turns.reverse();
+        Ok(turns)
+    }
+
+    pub async fn delete_session(&self, session_id: &SessionKey) -> Result<(), Error> {
+        self.db
+            .query("DELETE FROM turn WHERE session_id = $session")
+            .bind(("session", session_id.clone()))
+            .await?
+            .check()
+            .context("Unable to delete session")?;
+        Ok(())
+    }
+
     pub async fn get_vector_indexes(&self, id: Thing) -> Result<Vec<VectorIndex>, Error> {
         let mut result = self
             .db
-            .query("SELECT * FROM vector_index WHERE content_id = $content")
+            .query(format!(
+                "SELECT * FROM {} WHERE content_id = $content",
+                self.tables.chunks
+            ))
             .bind(("content", id.clone()))
             .await?;
         let vindexes: Vec<VectorIndex> = result.take(0)?;
         Ok(vindexes)
     }
 
-    #[allow(dead_code)]
     pub async fn get_content(&self, content_id: Thing) -> Result<Content, Error> {
-        let result: Vec<Content> = self.db.select(content_id.to_string()).await?;
+        let content = self.find_content(content_id).await?;
+        content.context("No content found")
+    }
 
-        let content = result.into_iter().next().context("No content found")?;
+    // `None` when no content has this id, errors are left for database failures
+    pub async fn find_content(&self, content_id: Thing) -> Result<Option<Content>, Error> {
+        // select by record id, a "content:<id>" string would be rejected as a table name
+        let mut result = self
+            .db
+            .query("SELECT * FROM $id")
+            .bind(("id", content_id))
+            .await?;
+        let content: Option<Content> = result.take(0)?;
         Ok(content)
     }
 
@@ -226,22 +880,378 @@ impl VDB {
         content_id: Thing,
         upper: u16,
         lower: u16,
-        chunk_number: u16,
+        chunk_number: u32,
     ) -> Result<Vec<VectorIndex>, Error> {
         // guard statement to check underflow
-        let start = if chunk_number > lower {
-            chunk_number - lower
-        } else {
-            0
-        };
+        let start = chunk_number.saturating_sub(u32::from(lower));
+        let end = chunk_number.saturating_add(u32::from(upper));
+        self.get_chunk_range(content_id, start, end).await
+    }
 
-        let mut result = self.db
-            .query("SELECT * FROM vector_index WHERE content_id = $content AND chunk_number >= $start AND chunk_number <= $end ORDER BY chunk_number ASC")
+    // chunks `start..=end` of one content, in document order
+    pub async fn get_chunk_range(
+        &self,
+        content_id: Thing,
+        start: u32,
+        end: u32,
+    ) -> Result<Vec<VectorIndex>, Error> {
+        let mut result = self
+            .db
+            .query(format!(
+                "SELECT * FROM {} WHERE content_id = $content \
+                 AND chunk_number >= $start AND chunk_number <= $end ORDER BY chunk_number ASC",
+                self.tables.chunks
+            ))
             .bind(("content", content_id.clone()))
             .bind(("start", start))
-            .bind(("end", chunk_number + upper))
+            .bind(("end", end))
             .await?;
         let vector_indexes = result.take(0)?;
         Ok(vector_indexes)
     }
+
+    // embeds every content again with the current model and chunking into a fresh generation of
+    // tables, then makes it live in one transaction. the live generation keeps answering until
+    // the switch. an interrupted run is resumed as long as the model is the same.
+    pub async fn reembed(
+        &self,
+        chunking: &ChunkOptions,
+        mut progress: impl FnMut(usize, usize, &Content),
+    ) -> anyhow::Result<ReembedReport, Error> {
+        chunking.check()?;
+        let model = self.embedder.model_id().to_string();
+        let live: Option<EmbeddingMeta> = self
+            .db
+            .select(("meta", "embedding"))
+            .await
+            .context("Unable to read embedding meta")?;
+        let live_generation = live.map(|meta| meta.generation).unwrap_or_default();
+
+        let pending: Option<ReembedState> = self
+            .db
+            .select(("meta", "reembed"))
+            .await
+            .context("Unable to read reembed state")?;
+        let state = match pending {
+            Some(state)
+                if state.model == model
+                    && state.dimension == self.dimension
+                    && state.query_prefix == self.embedder.query_prefix()
+                    && state.document_prefix == self.embedder.document_prefix()
+                    && state.pooling == Some(self.embedder.pooling())
+                    && state.nfkc == self.embedder.nfkc()
+                    && state.generation != live_generation =>
+            {
+                state
+            }
+            stale => {
+                // an interrupted run for another model is of no use
+                if let Some(stale) = stale.filter(|s| s.generation != live_generation) {
+                    drop_tables(&self.db, &Tables::generation(stale.generation)).await?;
+                }
+                let state = ReembedState {
+                    generation: live_generation + 1,
+                    model: model.clone(),
+                    dimension: self.dimension,
+                    query_prefix: self.embedder.query_prefix().to_string(),
+                    document_prefix: self.embedder.document_prefix().to_string(),
+                    pooling: Some(self.embedder.pooling()),
+                    nfkc: self.embedder.nfkc(),
+                    embedded: Vec::new(),
+                };
+                let _: Option<ReembedState> = self
+                    .db
+                    .upsert(("meta", "reembed"))
+                    .content(state.clone())
+                    .await
+                    .context("Unable to store reembed state")?;
+                state
+            }
+        };
+        let next = Tables::generation(state.generation);
+        define_indexes(&self.db, &next, self.dimension).await?;
+
+        // content forgotten since the interrupted run
+        self.db
+            .query(format!(
+                "DELETE FROM {} WHERE content_id NOT IN (SELECT VALUE id FROM content); \
+                 DELETE FROM {} WHERE content_id NOT IN (SELECT VALUE id FROM content)",
+                next.postings, next.chunks
+            ))
+            .await?
+            .check()
+            .context("Unable to clean up reembed tables")?;
+
+        let mut result = self
+            .db
+            .query("SELECT * FROM content ORDER BY created_at ASC")
+            .await?;
+        let contents: Vec<Content> = result.take(0)?;
+        let finished = state
+            .embedded
+            .iter()
+            .map(|e| (e.id.to_string(), e.content_hash.clone()))
+            .collect::<HashMap<String, Option<String>>>();
+        let is_finished = |c: &Content| finished.get(&c.id.to_string()) == Some(&c.content_hash);
+        let total = contents.len();
+        let resumed = contents.iter().filter(|c| is_finished(c)).count();
+
+        let mut done = resumed;
+        for content in contents.iter().filter(|c| !is_finished(c)) {
+            // chunks of a content the interrupted run was halfway through
+            self.db
+                .query(format!(
+                    "DELETE FROM {} WHERE content_id = $id; DELETE FROM {} WHERE content_id = $id",
+                    next.postings, next.chunks
+                ))
+                .bind(("id", content.id.clone()))
+                .await?
+                .check()
+                .context("Unable to clear partially reembedded content")?;
+
+            let chunks = self.rechunk(content, chunking).await?;
+            self.insert_chunks(&next, &content.id, chunks)
+                .await
+                .with_context(|| format!("Unable to reembed {}", content.id))?;
+
+            let embedded = Reembedded {
+                id: content.id.clone(),
+                content_hash: content.content_hash.clone(),
+            };
+            self.db
+                .query("UPDATE meta:reembed SET embedded += $embedded")
+                .bind(("embedded", embedded))
+                .await?
+                .check()
+                .context("Unable to record reembed progress")?;
+            done += 1;
+            progress(done, total, content);
+        }
+
+        // queries see either the old or the new generation, never a mix
+        let meta = EmbeddingMeta {
+            dimension: self.dimension,
+            model: Some(model),
+            generation: state.generation,
+            query_prefix: Some(self.embedder.query_prefix().to_string()),
+            document_prefix: Some(self.embedder.document_prefix().to_string()),
+            pooling: Some(self.embedder.pooling()),
+            nfkc: Some(self.embedder.nfkc()),
+        };
+        self.db
+            .query(
+                "BEGIN TRANSACTION; \
+                 UPSERT meta:embedding CONTENT $meta; \
+                 DELETE meta:reembed; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("meta", meta))
+            .await?
+            .check()
+            .context("Unable to switch to the new embeddings")?;
+        drop_tables(&self.db, &self.tables).await?;
+        // vectors of the old model cannot be hit anymore
+        self.cache.evict().await?;
+
+        Ok(ReembedReport {
+            generation: state.generation,
+            contents: total,
+            resumed,
+        })
+    }
+
+    // cuts a stored content again the way it was ingested, markdown along its headings. the
+    // metadata of its first chunk tells how that was.
+    async fn rechunk(
+        &self,
+        content: &Content,
+        chunking: &ChunkOptions,
+    ) -> Result<Vec<Chunk>, Error> {
+        let first = self
+            .get_chunk_range(content.id.clone(), 0, 0)
+            .await?
+            .into_iter()
+            .next();
+        let (mut metadata, markdown) = match first {
+            Some(chunk) => {
+                let markdown = chunk.metadata["chunker"]["format"] == "markdown";
+                (chunk.metadata, markdown)
+            }
+            None => (json!({"source": content.title}), false),
+        };
+        // the chunker produces these again
+        if let Some(fields) = metadata.as_object_mut() {
+            for field in ["chunker", "breadcrumb", "headings"] {
+                fields.remove(field);
+            }
+        }
+
+        if markdown {
+            chunk_markdown(&content.text, &metadata, chunking, self.embedder.clone())
+        } else {
+            self.chunk_text(&content.text, metadata, chunking)
+        }
+    }
+
+    // vectors of another size would silently never match, or break the hnsw index
+    fn check_dimension(&self, vector: &[f32]) -> Result<(), Error> {
+        if vector.len() != self.dimension {
+            bail!(
+                "vector has {} dimensions but this database stores {}-dimensional vectors",
+                vector.len(),
+                self.dimension
+            );
+        }
+        Ok(())
+    }
+}
+
+// a database remembers the model, prefixes, pooling, normalization and dimension of its vectors
+// on first use. databases created before that was recorded take the dimension from their first
+// stored vector and adopt the settings they are opened with. returns the live generation.
+async fn check_embedding_meta(
+    db: &Surreal<Db>,
+    embedder: &dyn EmbeddingEngine,
+) -> Result<u32, Error> {
+    let model = embedder.model_id();
+    let dimension = embedder.dimension();
+    let (query_prefix, document_prefix) = (embedder.query_prefix(), embedder.document_prefix());
+    let (pooling, nfkc) = (embedder.pooling(), embedder.nfkc());
+    let recorded: Option<EmbeddingMeta> = db
+        .select(("meta", "embedding"))
+        .await
+        .context("Unable to read embedding meta")?;
+    let stored = match &recorded {
+        Some(meta) => Some(meta.clone()),
+        None => {
+            let mut result = db
+                .query("SELECT array::len(vector) AS dimension FROM vector_index LIMIT 1")
+                .await?;
+            result.take::<Option<EmbeddingMeta>>(0)?
+        }
+    };
+
+    if let Some(meta) = &stored {
+        if let Some(stored_model) = meta.model.as_deref().filter(|m| *m != model) {
+            bail!(
+                "./ragme.db was embedded with {stored_model} but {model} was selected, \
+                 pass --embedding-model {stored_model} or run `reembed` to switch models"
+            );
+        }
+        // the same model with other prefixes puts queries and chunks in different places
+        let prefixes = [
+            ("--query-prefix", &meta.query_prefix, query_prefix),
+            ("--document-prefix", &meta.document_prefix, document_prefix),
+        ];
+        for (flag, stored, selected) in prefixes {
+            if let Some(stored) = stored.as_deref().filter(|p| *p != selected) {
+                bail!(
+                    "./ragme.db was embedded with {flag} {stored:?} but {selected:?} was \
+                     selected, pass {flag} {stored:?} or run `reembed` to switch prefixes"
+                );
+            }
+        }
+        // other pooling or normalization gives vectors the stored ones cannot be compared to
+        if let Some(stored) = meta.pooling.filter(|p| *p != pooling) {
+            bail!(
+                "./ragme.db was embedded with --pooling {} but {} was selected, \
+                 pass --pooling {} or run `reembed` to switch pooling",
+                stored.as_str(),
+                pooling.as_str(),
+                stored.as_str()
+            );
+        }
+        if let Some(stored) = meta.nfkc.filter(|n| *n != nfkc) {
+            let (was, now) = if stored {
+                ("with", "without")
+            } else {
+                ("without", "with")
+            };
+            bail!(
+                "./ragme.db was embedded {was} --nfkc but this run is {now} it, \
+                 change --nfkc or run `reembed` to switch normalization"
+            );
+        }
+        if meta.dimension != dimension {
+            bail!(
+                "./ragme.db holds {}-dimensional vectors but the embedding model produces {}, \
+                 use the model the database was created with or run `reembed` to switch models",
+                meta.dimension,
+                dimension
+            );
+        }
+    }
+
+    let generation = stored.map(|meta| meta.generation).unwrap_or_default();
+    let complete = recorded.is_some_and(|meta| {
+        meta.model.is_some()
+            && meta.query_prefix.is_some()
+            && meta.document_prefix.is_some()
+            && meta.pooling.is_some()
+            && meta.nfkc.is_some()
+    });
+    if !complete {
+        let _: Option<EmbeddingMeta> = db
+            .upsert(("meta", "embedding"))
+            .content(EmbeddingMeta {
+                dimension,
+                model: Some(model.to_string()),
+                generation,
+                query_prefix: Some(query_prefix.to_string()),
+                document_prefix: Some(document_prefix.to_string()),
+                pooling: Some(pooling),
+                nfkc: Some(nfkc),
+            })
+            .await
+            .context("Unable to store embedding meta")?;
+    }
+    Ok(generation)
+}
+
+// hex sha256 of a document's text
+fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+async fn drop_tables(db: &Surreal<Db>, tables: &Tables) -> Result<(), Error> {
+    db.query(format!(
+        "REMOVE TABLE IF EXISTS {}; REMOVE TABLE IF EXISTS {};",
+        tables.chunks, tables.postings
+    ))
+    .await?
+    .check()
+    .context("Unable to drop old vector tables")?;
+    Ok(())
+}
+
+async fn define_indexes(db: &Surreal<Db>, tables: &Tables, dimension: usize) -> Result<(), Error> {
+    // approximate nearest neighbour index used by the knn operator in `get_related_chunks`
+    db.query(format!(
+        "DEFINE INDEX IF NOT EXISTS vector_index_hnsw ON TABLE {} \
+         FIELDS vector HNSW DIMENSION {} DISTANCE COSINE TYPE F32",
+        tables.chunks, dimension
+    ))
+    .await?
+    .check()
+    .context("Unable to define vector index")?;
+
+    // bm25 index for keyword search. no stemming so identifiers and error codes match as typed.
+    db.query(format!(
+        "DEFINE ANALYZER IF NOT EXISTS chunk_analyzer TOKENIZERS blank, class FILTERS lowercase; \
+         DEFINE INDEX IF NOT EXISTS vector_index_fulltext ON TABLE {} \
+         FIELDS content_chunk SEARCH ANALYZER chunk_analyzer BM25",
+        tables.chunks
+    ))
+    .await?
+    .check()
+    .context("Unable to define full-text index")?;
+
+    db.query(format!(
+        "DEFINE INDEX IF NOT EXISTS sparse_posting_term ON TABLE {} FIELDS term",
+        tables.postings
+    ))
+    .await?
+    .check()
+    .context("Unable to define sparse posting index")?;
+    Ok(())
 }
@@ -0,0 +1,443 @@
+use scraper::{node::Node, ElementRef, Html};
+
+// never content: scripts, styling, site chrome and widgets
+const BOILERPLATE: &[&str] = &[
+    "script", "style", "noscript", "template", "nav", "footer", "aside", "form", "button",
+    "iframe", "svg", "canvas", "select",
+];
+
+// aria roles of site chrome
+const BOILERPLATE_ROLES: &[&str] = &[
+    "navigation",
+    "banner",
+    "contentinfo",
+    "search",
+    "complementary",
+];
+
+// elements that start a block of their own, everything else flows inline
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "body",
+    "dl",
+    "dt",
+    "dd",
+    "figure",
+    "figcaption",
+    "address",
+    "details",
+    "summary",
+    "hr",
+];
+
+// a web page as markdown, ready for the markdown chunker
+#[derive(Debug, Clone)]
+pub struct HtmlDocument {
+    // the `<title>`, or the first `<h1>` when there is none
+    pub title: Option<String>,
+    pub markdown: String,
+    // href of every link in the page body, in order of first appearance
+    pub links: Vec<String>,
+}
+
+// keeps the main content of a page: `<main>`, `role="main"` or confluence's `#main-content`
+// when present, else the page's only `<article>`, else the whole body. headings become `#`
+// lines, lists `-`/`1.` items, tables pipe tables and `<pre>` fenced code, so the markdown
+// chunker can cut along the page's structure.
+pub fn html_to_markdown(html: &str) -> HtmlDocument {
+    let document = Html::parse_document(html);
+    let root = document.root_element();
+
+    let title = elements(root)
+        .find(|e| e.value().name() == "title")
+        .map(|e| collapse(&e.text().collect::<String>()))
+        .filter(|t| !t.is_empty())
+        .or_else(|| {
+            elements(root)
+                .find(|e| e.value().name() == "h1")
+                .map(|e| collapse(&e.text().collect::<String>()))
+                .filter(|t| !t.is_empty())
+        });
+
+    let content = main_content(root)
+        .or_else(|| elements(root).find(|e| e.value().name() == "body"))
+        .unwrap_or(root);
+
+    let mut renderer = Renderer::default();
+    renderer.block(content);
+    renderer.flush();
+
+    HtmlDocument {
+        title,
+        markdown: renderer.blocks.join("\n\n"),
+        links: renderer.links,
+    }
+}
+
+#[derive(Default)]
+struct Renderer {
+    blocks: Vec<String>,
+    // text of the paragraph being built
+    inline: String,
+    links: Vec<String>,
+}
+
+impl Renderer {
+    fn block(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.inline.push_str(text),
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        self.element(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn element(&mut self, element: ElementRef) {
+        if is_boilerplate(element) {
+            return;
+        }
+        let name = element.value().name();
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                let level = usize::from(name.as_bytes()[1] - b'0');
+                let text = self.inline_text(element);
+                if !text.is_empty() {
+                    self.blocks.push(format!("{} {}", "#".repeat(level), text));
+                }
+            }
+            "ul" | "ol" => {
+                self.flush();
+                let mut lines = Vec::new();
+                self.list(element, 0, &mut lines);
+                if !lines.is_empty() {
+                    self.blocks.push(lines.join("\n"));
+                }
+            }
+            "table" => {
+                self.flush();
+                if let Some(table) = self.table(element) {
+                    self.blocks.push(table);
+                }
+            }
+            "pre" => {
+                self.flush();
+                let code = element.text().collect::<String>();
+                let code = code.trim_matches('\n');
+                if !code.trim().is_empty() {
+                    // highlighters mark the language as `<code class="language-rust">`
+                    let language = elements(element)
+                        .flat_map(|e| e.value().classes())
+                        .find_map(|class| class.strip_prefix("language-"))
+                        .unwrap_or_default();
+                    // the fence must be longer than any run of backticks in the code
+                    let longest = code.split(|c| c != '`').map(str::len).max();
+                    let fence = "`".repeat(longest.unwrap_or_default().max(2) + 1);
+                    self.blocks
+                        .push(format!("{fence}{language}\n{code}\n{fence}"));
+                }
+            }
+            "blockquote" => {
+                self.flush();
+                let mut quote = Renderer::default();
+                quote.block(element);
+                quote.flush();
+                self.links.append(&mut quote.links);
+                for block in quote.blocks {
+                    let lines = block.lines().map(|line| format!("> {line}"));
+                    self.blocks.push(lines.collect::<Vec<String>>().join("\n"));
+                }
+            }
+            "br" => self.inline.push('\n'),
+            "a" => {
+                self.link(element);
+                self.block(element);
+            }
+            _ if BLOCKS.contains(&name) => {
+                self.flush();
+                self.block(element);
+                self.flush();
+            }
+            _ => self.block(element),
+        }
+    }
+
+    // nested lists are indented under their item
+    fn list(&mut self, list: ElementRef, depth: usize, lines: &mut Vec<String>) {
+        let ordered = list.value().name() == "ol";
+        let items = list
+            .children()
+            .filter_map(ElementRef::wrap)
+            .filter(|e| e.value().name() == "li");
+        for (number, item) in items.enumerate() {
+            let marker = if ordered {
+                format!("{}.", number + 1)
+            } else {
+                "-".to_string()
+            };
+            let text = self.inline_text(item);
+            if !text.is_empty() {
+                lines.push(format!("{}{} {}", "  ".repeat(depth), marker, text));
+            }
+            for nested in elements(item).filter(|e| matches!(e.value().name(), "ul" | "ol")) {
+                // only the lists directly under this item, deeper ones come with their parent
+                if nearest_list(nested) == Some(list) {
+                    self.list(nested, depth + 1, lines);
+                }
+            }
+        }
+    }
+
+    // the first row is the header, like most exported tables
+    fn table(&mut self, table: ElementRef) -> Option<String> {
+        let mut rows = Vec::new();
+        for row in elements(table).filter(|e| e.value().name() == "tr") {
+            let cells = row
+                .children()
+                .filter_map(ElementRef::wrap)
+                .filter(|e| matches!(e.value().name(), "th" | "td"))
+                .map(|cell| self.inline_text(cell).replace('|', "\\|"))
+                .collect::<Vec<String>>();
+            if !cells.is_empty() {
+                rows.push(cells);
+            }
+        }
+        let columns = rows.iter().map(Vec::len).max()?;
+
+        let mut lines = Vec::with_capacity(rows.len() + 1);
+        for (i, mut cells) in rows.into_iter().enumerate() {
+            cells.resize(columns, String::new());
+            lines.push(format!("| {} |", cells.join(" | ")));
+            if i == 0 {
+                lines.push(format!("|{}", " --- |".repeat(columns)));
+            }
+        }
+        Some(lines.join("\n"))
+    }
+
+    // the text of an element on one line, without nested lists and tables
+    fn inline_text(&mut self, element: ElementRef) -> String {
+        let mut text = String::new();
+        self.collect_text(element, &mut text);
+        collapse(&text)
+    }
+
+    fn collect_text(&mut self, element: ElementRef, text: &mut String) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(t) => text.push_str(t),
+                Node::Element(_) => {
+                    let Some(child) = ElementRef::wrap(child) else {
+                        continue;
+                    };
+                    let name = child.value().name();
+                    if is_boilerplate(child) || matches!(name, "ul" | "ol" | "table") {
+                        continue;
+                    }
+                    if name == "a" {
+                        self.link(child);
+                    }
+                    // keep words of adjacent blocks apart: <p>a</p><p>b</p>
+                    let spaced = BLOCKS.contains(&name) || name == "br";
+                    if spaced {
+                        text.push(' ');
+                    }
+                    self.collect_text(child, text);
+                    if spaced {
+                        text.push(' ');
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn link(&mut self, anchor: ElementRef) {
+        let Some(href) = anchor.value().attr("href").map(str::trim) else {
+            return;
+        };
+        // in-page anchors and scripts lead nowhere
+        if href.is_empty() || href.starts_with('#') || href.starts_with("javascript:") {
+            return;
+        }
+        if !self.links.iter().any(|link| link == href) {
+            self.links.push(href.to_string());
+        }
+    }
+
+    fn flush(&mut self) {
+        let text = collapse(&self.inline);
+        if !text.is_empty() {
+            self.blocks.push(text);
+        }
+        self.inline.clear();
+    }
+}
+
+fn elements<'a>(root: ElementRef<'a>) -> impl Iterator<Item = ElementRef<'a>> {
+    root.descendants().filter_map(ElementRef::wrap)
+}
+
+// a page with several articles is a listing, none of them is the main content
+fn main_content(root: ElementRef) -> Option<ElementRef> {
+    let main = elements(root).find(|e| {
+        let element = e.value();
+        element.name() == "main"
+            || element.attr("role") == Some("main")
+            || element.id() == Some("main-content")
+    });
+    main.or_else(|| {
+        let mut articles = elements(root).filter(|e| e.value().name() == "article");
+        let article = articles.next()?;
+        articles.next().is_none().then_some(article)
+    })
+}
+
+fn is_boilerplate(element: ElementRef) -> bool {
+    // the site header is chrome, the header of an article holds its title
+    if element.value().name() == "header" {
+        let in_article = element
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .any(|e| matches!(e.value().name(), "main" | "article"));
+        if !in_article {
+            return true;
+        }
+    }
+    let element = element.value();
+    BOILERPLATE.contains(&element.name())
+        || element.attr("hidden").is_some()
+        || element.attr("aria-hidden") == Some("true")
+        || element
+            .attr("role")
+            .is_some_and(|role| BOILERPLATE_ROLES.contains(&role))
+}
+
+// the closest enclosing `ul`/`ol` of a nested list
+fn nearest_list(list: ElementRef) -> Option<ElementRef> {
+    list.ancestors()
+        .filter_map(ElementRef::wrap)
+        .find(|e| matches!(e.value().name(), "ul" | "ol"))
+}
+
+// html whitespace is not significant, runs of it become one space
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_page_structure() {
+        let cases = [
+            (
+                "nested lists",
+                "<ul><li>a<ul><li>b<ol><li>c</li></ol></li></ul></li><li>d</li></ul>",
+                "- a\n  - b\n    1. c\n- d",
+            ),
+            (
+                "table with thead",
+                "<table><thead><tr><th>n</th><th>v</th></tr></thead>\
+                 <tbody><tr><td>1</td><td>a|b</td></tr></tbody></table>",
+                "| n | v |\n| --- | --- |\n| 1 | a\\|b |",
+            ),
+            (
+                "header outside an article",
+                "<header>Site</header><p>text</p>",
+                "text",
+            ),
+            (
+                "header inside an article",
+                "<header>Site</header><article><header><h1>Title</h1></header>\
+                 <p>text</p></article>",
+                "# Title\n\ntext",
+            ),
+            (
+                "pre",
+                "<pre>line 1\n  line 2\n</pre>",
+                "```\nline 1\n  line 2\n```",
+            ),
+            (
+                "pre with a language",
+                "<pre><code class=\"hljs language-rust\">fn main() {}</code></pre>",
+                "```rust\nfn main() {}\n```",
+            ),
+            (
+                "pre with a fence inside",
+                "<pre>```\ncode\n```</pre>",
+                "````\n```\ncode\n```\n````",
+            ),
+            (
+                "blockquote",
+                "<blockquote><p>one</p><p>two</p></blockquote>",
+                "> one\n\n> two",
+            ),
+            (
+                "main over article",
+                "<article><p>teaser</p></article><main><p>body</p></main>",
+                "body",
+            ),
+            (
+                "role main",
+                "<nav>menu</nav><div role=\"main\"><p>body</p></div>",
+                "body",
+            ),
+            (
+                "confluence main content",
+                "<div id=\"main-content\"><p>body</p></div><p>footer text</p>",
+                "body",
+            ),
+            (
+                "several articles",
+                "<article><p>one</p></article><article><p>two</p></article>",
+                "one\n\ntwo",
+            ),
+        ];
+        for (name, html, markdown) in cases {
+            let page = html_to_markdown(&format!("<html><body>{html}</body></html>"));
+            assert_eq!(page.markdown, markdown, "{name}");
+        }
+    }
+
+    #[test]
+    fn title_falls_back_to_the_first_h1() {
+        let cases = [
+            (
+                "<title> Page  title </title><h1>Heading</h1>",
+                Some("Page title"),
+            ),
+            (
+                "<title> </title><h1>Heading</h1><h1>Other</h1>",
+                Some("Heading"),
+            ),
+            ("<p>no title</p>", None),
+        ];
+        for (html, title) in cases {
+            let page = html_to_markdown(html);
+            assert_eq!(page.title.as_deref(), title, "{html}");
+        }
+    }
+
+    #[test]
+    fn links_leave_out_anchors_and_scripts() {
+        let page = html_to_markdown(
+            "<p><a href=\"#top\">top</a> <a href=\"javascript:void(0)\">menu</a> \
+             <a href=\" https://example.com/a \">a</a> <a href=\"/b\">b</a> \
+             <a href=\"https://example.com/a\">again</a> <a>none</a></p>",
+        );
+
+        assert_eq!(page.links, ["https://example.com/a", "/b"]);
+        assert_eq!(page.markdown, "top menu a b again none");
+    }
+}
@@ -1,62 +1,498 @@
-use anyhow::Context;
+use anyhow::{bail, Context};
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    WalkBuilder,
+};
 use serde_json::json;
 use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::UNIX_EPOCH,
+};
+use surrealdb::{sql::Thing, Datetime};
+use tokio::io::AsyncReadExt;
+
+use crate::data::{
+    chunker::ChunkOptions,
+    database::{Ingested, VDB},
+    html::html_to_markdown,
 };
-use surrealdb::Datetime;
 
-use crate::data::database::VDB;
+// file types `parse_file` can read
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["md", "markdown", "html", "htm", "txt", "pdf"];
+
+// bytes looked at to tell a binary file from text, like git does
+const BINARY_SNIFF_LEN: usize = 8000;
+
+// which files of a directory tree are ingested. `.gitignore`, `.ignore` and hidden files are
+// always honored, even for included files. the globs are matched against paths relative to the
+// directory.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    // only files matching one of these, every supported file when empty
+    pub include: Vec<String>,
+    // files matching one of these are left out, even when included
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum FileOutcome {
+    Ingested(Ingested),
+    // the file is gone, so is the content read from it
+    Removed(Thing),
+    // not a file we ingest, e.g. a binary or an unsupported type
+    Skipped(String),
+    Failed(anyhow::Error),
+}
+
+// what happened to one file of a directory
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub outcome: FileOutcome,
+}
+
+impl FileReport {
+    pub fn status(&self) -> &'static str {
+        match &self.outcome {
+            FileOutcome::Ingested(ingested) => ingested.status.as_str(),
+            FileOutcome::Removed(_) => "removed",
+            FileOutcome::Skipped(_) => "skipped",
+            FileOutcome::Failed(_) => "failed",
+        }
+    }
+
+    // the content id, or why the file was not ingested
+    pub fn detail(&self) -> String {
+        match &self.outcome {
+            FileOutcome::Ingested(ingested) => ingested.content.id.to_string(),
+            FileOutcome::Removed(id) => id.to_string(),
+            FileOutcome::Skipped(reason) => reason.clone(),
+            FileOutcome::Failed(e) => format!("{e:#}"),
+        }
+    }
+}
 
 // 1. take in the path name
 // 2. open the file
 // 3. parse the content in the file
 // 4. insert the content
-pub async fn ingest_via_txt(vdb: &Arc<VDB>, path: &PathBuf) -> anyhow::Result<()> {
-    let file_name = path
-        .file_name()
-        .context("bopes")?
-        .to_str()
-        .context("shag")?
-        .to_string();
-
-    let file = File::open(path).context("unable to open file").unwrap();
-    let reader = BufReader::new(file);
-    let content = reader
-        .lines()
-        .map(|l| l.unwrap())
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    // content now is continous with `\n`
-    let _content = vdb
-        .process_content(
-            &file_name,
-            &content,
-            json!({"source": file_name, "upload_time": Datetime::default()}),
-        )
+pub async fn ingest_path(
+    vdb: &Arc<VDB>,
+    path: &Path,
+    chunking: &ChunkOptions,
+) -> anyhow::Result<Ingested> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    if !metadata.is_file() {
+        bail!("{} is not a file", path.display());
+    }
+    let bytes = tokio::fs::read(path).await.context("unable to open file")?;
+    // the same file reached through another relative path is still the same source
+    let source = tokio::fs::canonicalize(path).await?;
+    let source = source.to_str().context("path is not valid utf-8")?;
+    let mut ingested = ingest_bytes(vdb, file_name(path)?, source, &bytes, chunking).await?;
+
+    // `watch` compares it to tell which files changed while it was not running
+    if let Some(mtime) = mtime_ms(&metadata) {
+        vdb.set_mtime(&ingested.content.id, mtime).await?;
+        ingested.content.mtime_ms = Some(mtime);
+    }
+    Ok(ingested)
+}
+
+// modification time in milliseconds since the unix epoch, if the platform has one
+pub fn mtime_ms(metadata: &std::fs::Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_millis()).ok()
+}
+
+// the text of a file, read but not stored yet
+#[derive(Debug)]
+pub struct ParsedFile {
+    pub title: String,
+    pub text: String,
+    // markdown is chunked along its headings, other text by the chunk options alone
+    pub markdown: bool,
+    // outbound links of an html page
+    pub links: Option<Vec<String>>,
+}
+
+// reads a file by the type its name says. an error here is the file's fault (unsupported type,
+// broken encoding or pdf), nothing has touched the database yet.
+pub fn parse_file(file_name: &str, bytes: &[u8]) -> anyhow::Result<ParsedFile> {
+    let parsed = match extension(Path::new(file_name)).as_deref() {
+        Some("md" | "markdown") => {
+            let text = std::str::from_utf8(bytes).context("file is not valid utf-8")?;
+            ParsedFile {
+                title: file_name.to_string(),
+                text: text.to_string(),
+                markdown: true,
+                links: None,
+            }
+        }
+        Some("html" | "htm") => {
+            // saved pages are not always utf-8, stray bytes should not lose the whole page
+            let html = String::from_utf8_lossy(bytes);
+            let page = html_to_markdown(&html);
+            ParsedFile {
+                title: page.title.unwrap_or_else(|| file_name.to_string()),
+                text: page.markdown,
+                markdown: true,
+                links: Some(page.links),
+            }
+        }
+        Some("txt") => {
+            let text = std::str::from_utf8(bytes).context("file is not valid utf-8")?;
+            ParsedFile {
+                title: file_name.to_string(),
+                // content now is continous with `\n`
+                text: text.lines().collect::<Vec<&str>>().join("\n"),
+                markdown: false,
+                links: None,
+            }
+        }
+        Some("pdf") => ParsedFile {
+            title: file_name.to_string(),
+            text: pdf_extract::extract_text_from_mem(bytes)
+                .context("unable to extract pdf text")?,
+            markdown: false,
+            links: None,
+        },
+        Some(ext) => bail!("unsupported file type: {}", ext),
+        None => bail!("file has no extension"),
+    };
+    Ok(parsed)
+}
+
+// chunks, embeds and stores a parsed file. `source` identifies the file across uploads, a
+// second upload of it updates the content.
+pub async fn store_file(
+    vdb: &Arc<VDB>,
+    file_name: &str,
+    source: &str,
+    parsed: ParsedFile,
+    chunking: &ChunkOptions,
+) -> anyhow::Result<Ingested> {
+    let metadata = json!({"source": file_name, "upload_time": Datetime::default()});
+    let (title, text) = (parsed.title.as_str(), parsed.text.as_str());
+    let mut ingested = if parsed.markdown {
+        vdb.process_markdown(title, Some(source), text, metadata, chunking)
+            .await?
+    } else {
+        vdb.process_content(title, Some(source), text, metadata, chunking)
+            .await?
+    };
+    if let Some(links) = parsed.links {
+        // the same for every chunk of the page, so they are kept with the content
+        vdb.set_links(&ingested.content.id, &links).await?;
+        ingested.content.links = Some(links);
+    }
+    Ok(ingested)
+}
+
+// ingest an in-memory file (e.g. an http upload), the file name decides how it is parsed
+pub async fn ingest_bytes(
+    vdb: &Arc<VDB>,
+    file_name: &str,
+    source: &str,
+    bytes: &[u8],
+    chunking: &ChunkOptions,
+) -> anyhow::Result<Ingested> {
+    let parsed = parse_file(file_name, bytes)?;
+    store_file(vdb, file_name, source, parsed, chunking).await
+}
+
+// ingests every supported file below `root`. a file that fails is reported and the walk goes
+// on, `on_file` is called as each file is done.
+pub async fn ingest_dir(
+    vdb: &Arc<VDB>,
+    root: &Path,
+    walk: &WalkOptions,
+    chunking: &ChunkOptions,
+    mut on_file: impl FnMut(&FileReport),
+) -> anyhow::Result<Vec<FileReport>> {
+    let listing = {
+        let root = root.to_path_buf();
+        let walk = walk.clone();
+        tokio::task::spawn_blocking(move || list_files(&root, &walk)).await??
+    };
+
+    let mut reports = Vec::with_capacity(listing.errors.len() + listing.files.len());
+    for report in listing.errors {
+        on_file(&report);
+        reports.push(report);
+    }
+    for path in listing.files {
+        let outcome = ingest_file(vdb, &path, chunking).await;
+        let report = FileReport { path, outcome };
+        on_file(&report);
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+// one file of a walk, skipped, ingested or failed
+pub(crate) async fn ingest_file(
+    vdb: &Arc<VDB>,
+    path: &Path,
+    chunking: &ChunkOptions,
+) -> FileOutcome {
+    match skip_reason(path).await {
+        Ok(Some(reason)) => FileOutcome::Skipped(reason),
+        Ok(None) => match ingest_path(vdb, path, chunking).await {
+            Ok(ingested) => FileOutcome::Ingested(ingested),
+            Err(e) => FileOutcome::Failed(e),
+        },
+        Err(e) => FileOutcome::Failed(e),
+    }
+}
+
+// the globs of a walk below a directory. excludes prune the walk, includes only pick among the
+// files it found: a matching include override would let a file past the ignore files.
+pub(crate) struct WalkGlobs {
+    include: Override,
+    exclude: Override,
+}
+
+impl WalkGlobs {
+    pub(crate) fn new(root: &Path, walk: &WalkOptions) -> anyhow::Result<Self> {
+        let mut include = OverrideBuilder::new(root);
+        for glob in &walk.include {
+            include
+                .add(glob)
+                .with_context(|| format!("invalid include glob {glob}"))?;
+        }
+        let mut exclude = OverrideBuilder::new(root);
+        for glob in &walk.exclude {
+            exclude
+                .add(&format!("!{glob}"))
+                .with_context(|| format!("invalid exclude glob {glob}"))?;
+        }
+        Ok(Self {
+            include: include.build()?,
+            exclude: exclude.build()?,
+        })
+    }
+
+    // whether the globs let a file below the directory through, ignore files aside
+    pub(crate) fn allows(&self, path: &Path) -> bool {
+        if self.include.matched(path, false).is_ignore() {
+            return false;
+        }
+        // an excluded directory leaves out everything below it
+        let root = self.exclude.path();
+        !path
+            .ancestors()
+            .take_while(|p| p.starts_with(root) && *p != root)
+            .enumerate()
+            .any(|(depth, p)| self.exclude.matched(p, depth > 0).is_ignore())
+    }
+}
+
+// what a walk found: the files in a stable order and the entries it could not read
+pub(crate) struct Listing {
+    pub files: Vec<PathBuf>,
+    pub errors: Vec<FileReport>,
+}
+
+// files below `root` after ignore files and globs. an unreadable directory or a broken link is
+// reported and the walk goes on.
+pub(crate) fn list_files(root: &Path, walk: &WalkOptions) -> anyhow::Result<Listing> {
+    let globs = WalkGlobs::new(root, walk)?;
+    let walker = WalkBuilder::new(root)
+        .overrides(globs.exclude.clone())
+        // a docs tree is often not a git checkout of its own
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    let mut listing = Listing {
+        files: Vec::new(),
+        errors: Vec::new(),
+    };
+    for entry in walker {
+        match entry {
+            Ok(entry) => {
+                if entry.file_type().is_some_and(|t| t.is_file()) && globs.allows(entry.path()) {
+                    listing.files.push(entry.into_path());
+                }
+            }
+            Err(e) => listing.errors.push(FileReport {
+                path: error_path(&e).unwrap_or(root).to_path_buf(),
+                outcome: FileOutcome::Failed(e.into()),
+            }),
+        }
+    }
+    Ok(listing)
+}
+
+// the entry a walk error is about, if it says
+fn error_path(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+// unsupported types and binary files are skipped rather than failed
+async fn skip_reason(path: &Path) -> anyhow::Result<Option<String>> {
+    let extension = extension(path).unwrap_or_default();
+    if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+        return Ok(Some("unsupported file type".to_string()));
+    }
+    // pdfs are binary by nature, their text is extracted
+    if extension == "pdf" {
+        return Ok(None);
+    }
+    let mut head = Vec::with_capacity(BINARY_SNIFF_LEN);
+    tokio::fs::File::open(path)
+        .await?
+        .take(BINARY_SNIFF_LEN as u64)
+        .read_to_end(&mut head)
         .await?;
-    Ok(())
+    if head.contains(&0) {
+        return Ok(Some("binary file".to_string()));
+    }
+    Ok(None)
 }
 
-pub async fn ingest_via_pdf(vdb: &Arc<VDB>, path: &PathBuf) -> anyhow::Result<()> {
-    let bytes = std::fs::read(path.clone()).unwrap();
-    let out = pdf_extract::extract_text_from_mem(&bytes).unwrap();
+pub async fn ingest_note(
+    vdb: &Arc<VDB>,
+    note: &str,
+    chunking: &ChunkOptions,
+) -> anyhow::Result<Ingested> {
+    let metadata = json!({"source": "note", "kind": "remember"});
+    let ingested = vdb
+        .process_content("note", None, note, metadata, chunking)
+        .await?;
+    Ok(ingested)
+}
+
+// lowercased, `README.MD` is markdown too
+fn extension(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?;
+    Some(extension.to_ascii_lowercase())
+}
 
-    let file_name = path
-        .file_name()
-        .context("bopes")?
+fn file_name(path: &Path) -> anyhow::Result<&str> {
+    path.file_name()
+        .context("path has no file name")?
         .to_str()
-        .context("shag")?;
-
-    let _content = vdb
-        .process_content(
-            &file_name,
-            &out,
-            json!({"source": file_name, "upload_time": Datetime::default()}),
-        )
-        .await?;
-    Ok(())
+        .context("file name is not valid utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a directory tree under the system temp dir, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str, files: &[&str]) -> anyhow::Result<Self> {
+            let root = std::env::temp_dir().join(format!("ragme-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for file in files {
+                let path = root.join(file);
+                std::fs::create_dir_all(path.parent().context("file has no parent")?)?;
+                std::fs::write(path, "text")?;
+            }
+            Ok(Self(root))
+        }
+
+        fn list(&self, include: &[&str], exclude: &[&str]) -> anyhow::Result<Vec<String>> {
+            let walk = WalkOptions {
+                include: include.iter().map(|g| g.to_string()).collect(),
+                exclude: exclude.iter().map(|g| g.to_string()).collect(),
+            };
+            let listing = list_files(&self.0, &walk)?;
+            assert!(listing.errors.is_empty());
+            listing
+                .files
+                .iter()
+                .map(|path| {
+                    let relative = path.strip_prefix(&self.0)?.to_str();
+                    Ok(relative
+                        .context("path is not valid utf-8")?
+                        .replace('\\', "/"))
+                })
+                .collect()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const TREE: &[&str] = &[
+        ".gitignore",
+        ".hidden/notes.md",
+        "a.md",
+        "build/out.md",
+        "docs/b.md",
+        "docs/c.txt",
+        "drafts/d.md",
+        "secret.md",
+    ];
+
+    #[test]
+    fn includes_keep_ignore_files_and_hidden_files() -> anyhow::Result<()> {
+        let tree = Scratch::new("include", TREE)?;
+        std::fs::write(tree.0.join(".gitignore"), "build/\nsecret.md\n")?;
+
+        assert_eq!(
+            tree.list(&["**/*.md"], &[])?,
+            ["a.md", "docs/b.md", "drafts/d.md"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn excludes_leave_out_whole_directories() -> anyhow::Result<()> {
+        let tree = Scratch::new("exclude", TREE)?;
+        std::fs::write(tree.0.join(".gitignore"), "build/\nsecret.md\n")?;
+
+        assert_eq!(tree.list(&["*.md"], &["drafts"])?, ["a.md", "docs/b.md"]);
+        let globs = WalkGlobs::new(
+            &tree.0,
+            &WalkOptions {
+                include: Vec::new(),
+                exclude: vec!["drafts".to_string()],
+            },
+        )?;
+        assert!(!globs.allows(&tree.0.join("drafts/gone.md")));
+        assert!(globs.allows(&tree.0.join("docs/gone.md")));
+        Ok(())
+    }
+
+    #[test]
+    fn extensions_ignore_case() {
+        assert_eq!(extension(Path::new("README.MD")).as_deref(), Some("md"));
+        assert_eq!(extension(Path::new("Guide.PDF")).as_deref(), Some("pdf"));
+        assert_eq!(extension(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn parse_errors_are_the_files_own() -> anyhow::Result<()> {
+        assert!(parse_file("image.png", b"png").is_err());
+        assert!(parse_file("Makefile", b"all:").is_err());
+        assert!(parse_file("notes.md", &[0xff, 0xfe]).is_err());
+
+        let parsed = parse_file("notes.txt", b"one\r\ntwo")?;
+        assert_eq!(parsed.text, "one\ntwo");
+        assert!(!parsed.markdown);
+        let page = parse_file("page.HTML", b"<title>Page</title><a href=\"/a\">a</a>")?;
+        assert_eq!(page.title, "Page");
+        assert!(page.markdown && page.links.is_some());
+        Ok(())
+    }
 }
@@ -0,0 +1,385 @@
+use anyhow::Result;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    ai::EmbeddingEngine,
+    data::chunker::{chunker_for, pack, Chunk, ChunkOptions, ChunkStrategy},
+};
+
+// separates the headings of a breadcrumb, "Guide > Install > Linux"
+pub const BREADCRUMB_SEPARATOR: &str = " > ";
+
+// a heading and everything up to the next heading of any level
+struct Section {
+    // titles of the enclosing headings, outermost first. empty for text before the first heading.
+    headings: Vec<String>,
+    // paragraphs, code blocks and tables in document order, starting with the heading line
+    blocks: Vec<String>,
+}
+
+// chunks never cross a heading. within a section, blocks are packed like paragraphs up to the
+// chunk size. fenced code blocks and tables are kept whole even when they are larger than a
+// chunk, unless the embedder would truncate them; those are cut by lines or rows. long prose
+// falls back to the configured chunking strategy.
+pub fn chunk_markdown(
+    text: &str,
+    metadata: &serde_json::Value,
+    options: &ChunkOptions,
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+) -> Result<Vec<Chunk>> {
+    let prose = chunker_for(options, embedder.clone());
+    let mut chunker = options.to_metadata();
+    chunker["format"] = json!("markdown");
+
+    let limit = embedder.max_document_tokens()?;
+    let mut chunks = Vec::new();
+    for section in split_sections(text) {
+        let blocks = section.blocks.iter().map(String::as_str).collect();
+        let pieces = pack(
+            embedder.as_ref(),
+            blocks,
+            options,
+            "\n\n",
+            &|block: &str| {
+                if !is_atomic(block) {
+                    prose.chunk(block)
+                } else if embedder.token_offsets(block)?.len() <= limit {
+                    Ok(vec![block.to_string()])
+                } else {
+                    split_atomic(block, options, limit, embedder.clone())
+                }
+            },
+        )?;
+
+        let mut metadata = metadata.clone();
+        if let Some(fields) = metadata.as_object_mut() {
+            fields.insert("chunker".to_string(), chunker.clone());
+            if !section.headings.is_empty() {
+                let breadcrumb = section.headings.join(BREADCRUMB_SEPARATOR);
+                fields.insert("breadcrumb".to_string(), json!(breadcrumb));
+                fields.insert("headings".to_string(), json!(section.headings));
+            }
+        }
+        chunks.extend(pieces.into_iter().map(|text| Chunk {
+            text,
+            metadata: metadata.clone(),
+        }));
+    }
+    Ok(chunks)
+}
+
+// only atx headings (`## Install`) start a section, `#` inside fenced code does not count
+fn split_sections(text: &str) -> Vec<Section> {
+    let mut sections = vec![Section {
+        headings: Vec::new(),
+        blocks: Vec::new(),
+    }];
+    // (level, title) of the headings enclosing the current line
+    let mut stack: Vec<(usize, String)> = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    let mut in_table = false;
+    // fence char and length of the code block we are in
+    let mut fence: Option<(char, usize)> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+
+        if let Some((marker, len)) = fence {
+            block.push(line);
+            if fence_length(trimmed, marker) >= len && trimmed.chars().all(|c| c == marker) {
+                flush_block(&mut sections, &mut block);
+                fence = None;
+            }
+            continue;
+        }
+
+        if let Some(opening) = opening_fence(trimmed) {
+            flush_block(&mut sections, &mut block);
+            fence = Some(opening);
+            block.push(line);
+            continue;
+        }
+
+        if let Some((level, title)) = heading(trimmed) {
+            flush_block(&mut sections, &mut block);
+            stack.retain(|(l, _)| *l < level);
+            stack.push((level, title));
+            sections.push(Section {
+                headings: stack.iter().map(|(_, t)| t.clone()).collect(),
+                blocks: vec![trimmed.to_string()],
+            });
+            continue;
+        }
+
+        // blank lines end paragraphs, and a table ends where its rows stop
+        let is_row = trimmed.starts_with('|');
+        if trimmed.is_empty() || is_row != in_table {
+            flush_block(&mut sections, &mut block);
+        }
+        if !trimmed.is_empty() {
+            in_table = is_row;
+            block.push(line);
+        }
+    }
+    // an unterminated code fence runs to the end of the document
+    flush_block(&mut sections, &mut block);
+
+    // a heading directly followed by a sub-heading has no text of its own
+    sections.retain(|s| s.blocks.len() > usize::from(!s.headings.is_empty()));
+    sections
+}
+
+fn flush_block(sections: &mut [Section], block: &mut Vec<&str>) {
+    if block.is_empty() {
+        return;
+    }
+    if let Some(section) = sections.last_mut() {
+        section.blocks.push(block.join("\n"));
+    }
+    block.clear();
+}
+
+fn heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    // closing hashes are decoration: "## Install ##"
+    let title = rest.trim().trim_end_matches('#').trim();
+    Some((level, title.to_string()))
+}
+
+fn opening_fence(line: &str) -> Option<(char, usize)> {
+    ['`', '~'].into_iter().find_map(|marker| {
+        let len = fence_length(line, marker);
+        (len >= 3).then_some((marker, len))
+    })
+}
+
+fn fence_length(line: &str, marker: char) -> usize {
+    line.chars().take_while(|c| *c == marker).count()
+}
+
+// code blocks and tables are kept whole
+fn is_atomic(block: &str) -> bool {
+    let block = block.trim_start();
+    block.starts_with("```") || block.starts_with("~~~") || block.starts_with('|')
+}
+
+// cuts a code block by lines and a table by rows, packed up to the chunk size. every piece
+// repeats the fences or the header rows, so it still reads as code or as a table.
+fn split_atomic(
+    block: &str,
+    options: &ChunkOptions,
+    // tokens the embedder takes in per chunk
+    limit: usize,
+    embedder: Arc<dyn EmbeddingEngine + Send + Sync>,
+) -> Result<Vec<String>> {
+    let lines = block.lines().collect::<Vec<&str>>();
+    let (head, tail) = match lines.first().and_then(|line| opening_fence(line.trim())) {
+        Some((marker, len)) => {
+            let closed = lines.len() > 1
+                && lines.last().is_some_and(|line| {
+                    let line = line.trim();
+                    fence_length(line, marker) >= len && line.chars().all(|c| c == marker)
+                });
+            (1, usize::from(closed))
+        }
+        None if lines.len() > 2 && is_separator_row(lines[1]) => (2, 0),
+        None => (0, 0),
+    };
+    let (head, body, tail) = (
+        &lines[..head],
+        &lines[head..lines.len() - tail],
+        &lines[lines.len() - tail..],
+    );
+
+    // the repeated lines count against every piece
+    let frame = [head, tail].concat().join("\n");
+    let inner = ChunkOptions {
+        strategy: ChunkStrategy::Tokens,
+        max_tokens: options
+            .max_tokens
+            .min(limit)
+            .saturating_sub(embedder.token_offsets(&frame)?.len())
+            .max(1),
+        overlap: 0,
+    };
+    // a single line longer than a chunk is cut into token windows
+    let windows = chunker_for(&inner, embedder.clone());
+    let pieces = pack(
+        embedder.as_ref(),
+        body.to_vec(),
+        &inner,
+        "\n",
+        &|line: &str| windows.chunk(line),
+    )?;
+
+    Ok(pieces
+        .iter()
+        .map(|piece| {
+            let mut lines = head.to_vec();
+            lines.push(piece);
+            lines.extend(tail);
+            lines.join("\n")
+        })
+        .collect())
+}
+
+// the `| --- | :---: |` row under a table header
+fn is_separator_row(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with('|') && line.contains('-') && line.chars().all(|c| "|-: ".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ai::embedding::MAX_INPUT_TOKENS,
+        data::chunker::tests::{options, Words},
+    };
+
+    fn chunks(text: &str, max_tokens: usize) -> Result<Vec<Chunk>> {
+        let options = options(ChunkStrategy::Paragraphs, max_tokens, 0);
+        chunk_markdown(text, &json!({}), &options, Arc::new(Words))
+    }
+
+    fn texts(chunks: &[Chunk]) -> Vec<&str> {
+        chunks.iter().map(|c| c.text.as_str()).collect()
+    }
+
+    #[test]
+    fn sections_carry_their_heading_path() {
+        let text = "intro\n\n# Guide\n\nabout\n\n## Install ##\n\nsteps\n\n# Usage\n\nrun it";
+        let sections = split_sections(text);
+
+        let headings = sections
+            .iter()
+            .map(|s| s.headings.join(BREADCRUMB_SEPARATOR))
+            .collect::<Vec<String>>();
+        assert_eq!(headings, ["", "Guide", "Guide > Install", "Usage"]);
+        assert_eq!(sections[0].blocks, ["intro"]);
+        assert_eq!(sections[2].blocks, ["## Install ##", "steps"]);
+    }
+
+    #[test]
+    fn hashes_in_code_are_not_headings() {
+        let text = "# Build\n\n```sh\n# not a heading\n\nmake\n```\n\n#hashtag";
+        let sections = split_sections(text);
+
+        assert_eq!(sections.len(), 1);
+        assert_eq!(
+            sections[0].blocks,
+            ["# Build", "```sh\n# not a heading\n\nmake\n```", "#hashtag"]
+        );
+    }
+
+    #[test]
+    fn tables_are_blocks_of_their_own() {
+        let text = "# Data\nbefore\n| a | b |\n| --- | --- |\n| 1 | 2 |\nafter";
+        let sections = split_sections(text);
+
+        assert_eq!(
+            sections[0].blocks,
+            [
+                "# Data",
+                "before",
+                "| a | b |\n| --- | --- |\n| 1 | 2 |",
+                "after"
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_headings_are_dropped() {
+        let sections = split_sections("# Guide\n## Install\ntext");
+
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].headings, ["Guide", "Install"]);
+    }
+
+    #[test]
+    fn chunks_record_the_breadcrumb() -> Result<()> {
+        let chunks = chunks("# Guide\n\n## Install\n\nrun make", 64)?;
+
+        assert_eq!(texts(&chunks), ["## Install\n\nrun make"]);
+        assert_eq!(chunks[0].metadata["breadcrumb"], "Guide > Install");
+        assert_eq!(chunks[0].metadata["headings"], json!(["Guide", "Install"]));
+        assert_eq!(chunks[0].metadata["chunker"]["format"], "markdown");
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_do_not_cross_headings() -> Result<()> {
+        let chunks = chunks("# A\n\none\n\n# B\n\ntwo", 64)?;
+
+        assert_eq!(texts(&chunks), ["# A\n\none", "# B\n\ntwo"]);
+        assert!(chunks[0].metadata.get("breadcrumb").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn code_larger_than_a_chunk_stays_whole() -> Result<()> {
+        let code = "```\nlet a = 1;\nlet b = 2;\n```";
+        let chunks = chunks(code, 4)?;
+
+        assert_eq!(texts(&chunks), [code]);
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_code_is_cut_by_lines() -> Result<()> {
+        let lines = (0..MAX_INPUT_TOKENS)
+            .map(|i| format!("call {i}"))
+            .collect::<Vec<String>>();
+        let code = format!("```rust\n{}\n```", lines.join("\n"));
+        let chunks = chunks(&code, 100)?;
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.text.starts_with("```rust\ncall "));
+            assert!(chunk.text.ends_with("\n```"));
+            assert!(Words.token_offsets(&chunk.text)?.len() <= 100);
+        }
+        let body = chunks
+            .iter()
+            .flat_map(|c| c.text.lines().filter(|l| !l.starts_with("```")))
+            .collect::<Vec<&str>>();
+        assert_eq!(body, lines);
+        Ok(())
+    }
+
+    #[test]
+    fn code_is_cut_once_special_tokens_would_push_it_past_the_model() -> Result<()> {
+        // the fences are a token each, so these are `tokens` tokens in all
+        let code = |tokens: usize| format!("```\n{}\n```", vec!["x"; tokens - 2].join("\n"));
+        let limit = Words.max_document_tokens()?;
+        assert_eq!(limit, MAX_INPUT_TOKENS - 2);
+
+        assert_eq!(chunks(&code(limit), 4)?.len(), 1);
+        assert!(chunks(&code(limit + 1), 4)?.len() > 1);
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_tables_repeat_their_header() -> Result<()> {
+        let rows = (0..MAX_INPUT_TOKENS)
+            .map(|i| format!("| {i} | x |"))
+            .collect::<Vec<String>>();
+        let table = format!("| n | v |\n| --- | --- |\n{}", rows.join("\n"));
+        let chunks = chunks(&table, 100)?;
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.text.starts_with("| n | v |\n| --- | --- |\n| "));
+            assert!(Words.token_offsets(&chunk.text)?.len() <= 100);
+        }
+        Ok(())
+    }
+}
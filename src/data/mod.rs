@@ -1,2 +1,7 @@
+pub mod chunker;
 pub mod database;
-pub mod ingest;
\ No newline at end of file
+pub mod html;
+pub mod ingest;
+pub mod markdown;
+pub mod retrieval;
+pub mod watch;
@@ -0,0 +1,189 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::ai::SAMPLE_LEN;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    // approximate nearest neighbours through the hnsw index
+    #[default]
+    Hnsw,
+    // cosine similarity over every row, slow but exact. kept to compare against hnsw.
+    Exact,
+}
+
+// largest `top_k` accepted, it also sizes the hnsw search and the bm25 limit
+pub const MAX_TOP_K: usize = 100;
+
+// smallest `context_budget` accepted: the answer's `SAMPLE_LEN` plus room for the prompt
+// template, the question and at least part of a passage
+pub const MIN_CONTEXT_BUDGET: usize = SAMPLE_LEN + 256;
+
+// knobs for how related chunks are looked up for a query
+#[derive(Debug, Clone)]
+pub struct RetrievalOptions {
+    // how many chunks a search returns before neighbours are added
+    pub top_k: usize,
+    // vector hits below this cosine similarity are dropped before fusion. bm25 and sparse
+    // scores are not on the same scale, their hits are never filtered by it.
+    pub min_similarity: f32,
+    // chunks pulled in around every hit to widen its context
+    pub neighbors_before: u16,
+    pub neighbors_after: u16,
+    // total tokens the prompt and the generated answer may use, phi-2 has a 2048 window
+    pub context_budget: usize,
+    pub search_mode: SearchMode,
+    // weights of the vector and bm25 rankings in reciprocal rank fusion, 0 disables a ranking
+    pub vector_weight: f32,
+    pub keyword_weight: f32,
+    // weight of splade sparse retrieval, ignored when no sparse model is loaded
+    pub sparse_weight: f32,
+    // rrf damping constant, larger values flatten the gap between top and lower ranks
+    pub rrf_k: f32,
+}
+
+impl Default for RetrievalOptions {
+    fn default() -> Self {
+        Self {
+            top_k: 4,
+            min_similarity: 0.0,
+            neighbors_before: 1,
+            neighbors_after: 1,
+            context_budget: 2048,
+            search_mode: SearchMode::default(),
+            vector_weight: 1.0,
+            keyword_weight: 1.0,
+            sparse_weight: 1.0,
+            rrf_k: 60.0,
+        }
+    }
+}
+
+impl RetrievalOptions {
+    // every field comes straight from flags and request bodies
+    pub fn check(&self) -> Result<()> {
+        if self.top_k == 0 || self.top_k > MAX_TOP_K {
+            bail!(
+                "top k must be between 1 and {MAX_TOP_K}, got {}",
+                self.top_k
+            );
+        }
+        if self.context_budget < MIN_CONTEXT_BUDGET {
+            bail!(
+                "context budget must be at least {MIN_CONTEXT_BUDGET} tokens, {SAMPLE_LEN} of \
+                 them are kept for the answer, got {}",
+                self.context_budget
+            );
+        }
+        let weights = [
+            ("vector", self.vector_weight),
+            ("keyword", self.keyword_weight),
+            ("sparse", self.sparse_weight),
+        ];
+        for (name, weight) in weights {
+            if !weight.is_finite() || weight < 0.0 {
+                bail!("{name} weight must be zero or positive, got {weight}");
+            }
+        }
+        if weights.iter().all(|(_, weight)| *weight == 0.0) {
+            bail!("at least one of the vector, keyword and sparse weights must be positive");
+        }
+        if !self.rrf_k.is_finite() || self.rrf_k <= 0.0 {
+            bail!("rrf k must be positive, got {}", self.rrf_k);
+        }
+        if !self.min_similarity.is_finite() {
+            bail!(
+                "min similarity must be a number, got {}",
+                self.min_similarity
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(RetrievalOptions::default().check().is_ok());
+    }
+
+    #[test]
+    fn rejects_negative_and_non_finite_weights() {
+        for weight in [-1.0, f32::NAN, f32::INFINITY] {
+            let options = RetrievalOptions {
+                keyword_weight: weight,
+                ..Default::default()
+            };
+            assert!(options.check().is_err(), "accepted weight {weight}");
+        }
+    }
+
+    #[test]
+    fn rejects_all_weights_zero() {
+        let options = RetrievalOptions {
+            vector_weight: 0.0,
+            keyword_weight: 0.0,
+            sparse_weight: 0.0,
+            ..Default::default()
+        };
+        assert!(options.check().is_err());
+
+        let keyword_only = RetrievalOptions {
+            vector_weight: 0.0,
+            sparse_weight: 0.0,
+            ..Default::default()
+        };
+        assert!(keyword_only.check().is_ok());
+    }
+
+    #[test]
+    fn rejects_top_k_out_of_range() {
+        for top_k in [0, MAX_TOP_K + 1] {
+            let options = RetrievalOptions {
+                top_k,
+                ..Default::default()
+            };
+            assert!(options.check().is_err(), "accepted top k {top_k}");
+        }
+        let options = RetrievalOptions {
+            top_k: MAX_TOP_K,
+            ..Default::default()
+        };
+        assert!(options.check().is_ok());
+    }
+
+    #[test]
+    fn rejects_context_budget_without_room_for_a_prompt() {
+        for context_budget in [0, SAMPLE_LEN, MIN_CONTEXT_BUDGET - 1] {
+            let options = RetrievalOptions {
+                context_budget,
+                ..Default::default()
+            };
+            assert!(
+                options.check().is_err(),
+                "accepted context budget {context_budget}"
+            );
+        }
+        let options = RetrievalOptions {
+            context_budget: MIN_CONTEXT_BUDGET,
+            ..Default::default()
+        };
+        assert!(options.check().is_ok());
+    }
+
+    #[test]
+    fn rejects_non_positive_rrf_k() {
+        for rrf_k in [0.0, -60.0, f32::NAN] {
+            let options = RetrievalOptions {
+                rrf_k,
+                ..Default::default()
+            };
+            assert!(options.check().is_err(), "accepted rrf k {rrf_k}");
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::{bail, Context};
+use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode, DebounceEventResult};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+use crate::data::{
+    chunker::ChunkOptions,
+    database::{Content, DATABASE_PATH, VDB},
+    ingest::{ingest_file, list_files, mtime_ms, FileOutcome, FileReport, WalkGlobs, WalkOptions},
+};
+
+// quiet time after the last event before the tree is synced, editors save in bursts
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+// files that failed to ingest and the modification time they failed at. a file is only tried
+// again once it changes, so each failure is reported once.
+pub type Failures = HashMap<PathBuf, Option<u64>>;
+
+// keeps the store in sync with the directory `root` until ctrl-c. the first sync picks up what
+// changed while nothing was watching, after that every burst of filesystem events triggers
+// another one. `on_file` is called for every file that was added, updated, removed or failed,
+// and with `root` itself when a whole sync fails.
+pub async fn watch_dir(
+    vdb: &Arc<VDB>,
+    root: &Path,
+    walk: &WalkOptions,
+    chunking: &ChunkOptions,
+    mut on_file: impl FnMut(&FileReport),
+) -> anyhow::Result<()> {
+    let root = tokio::fs::canonicalize(root)
+        .await
+        .with_context(|| format!("unable to read {}", root.display()))?;
+    if !root.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| {
+        let _ = tx.send(result);
+    })?;
+    debouncer
+        .watcher()
+        .watch(&root, RecursiveMode::Recursive)
+        .context("unable to watch directory")?;
+
+    // the database may live inside the watched tree, its own writes are not changes
+    let database = tokio::fs::canonicalize(DATABASE_PATH).await.ok();
+    // ctrl-c is only acted on between files, an ingest cut off halfway would leave a content
+    // with part of its chunks
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::new(Notify::new());
+    tokio::spawn({
+        let (stop, stopped) = (stop.clone(), stopped.clone());
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stop.store(true, Ordering::Relaxed);
+                stopped.notify_one();
+            }
+        }
+    });
+
+    let mut failures = Failures::new();
+    let mut changed = true;
+    loop {
+        if changed {
+            let synced = sync_dir(
+                vdb,
+                &root,
+                walk,
+                chunking,
+                &mut failures,
+                &stop,
+                &mut on_file,
+            )
+            .await;
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            // e.g. the database is busy, the next burst of events tries again
+            if let Err(e) = synced {
+                on_file(&FileReport {
+                    path: root.clone(),
+                    outcome: FileOutcome::Failed(e),
+                });
+            }
+        }
+
+        let result = tokio::select! {
+            result = rx.recv() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = stopped.notified() => break,
+        };
+        changed = match result {
+            Ok(events) => events
+                .iter()
+                .any(|event| !matches!(&database, Some(db) if event.path.starts_with(db))),
+            // events may have been lost, a full sync catches up on them
+            Err(_) => true,
+        };
+    }
+    Ok(())
+}
+
+// brings the contents this watch ingested from below `root` in line with the files there. a
+// file whose modification time matches the stored one is left alone, others are ingested
+// again (which is a no-op when only the time changed). a content is deleted only when its file
+// is gone and would still be ingested, contents of other uploads are never touched.
+pub async fn sync_dir(
+    vdb: &Arc<VDB>,
+    root: &Path,
+    walk: &WalkOptions,
+    chunking: &ChunkOptions,
+    failures: &mut Failures,
+    // set to stop after the file being synced
+    stop: &AtomicBool,
+    mut on_file: impl FnMut(&FileReport),
+) -> anyhow::Result<Vec<FileReport>> {
+    let listing = {
+        let root = root.to_path_buf();
+        let walk = walk.clone();
+        tokio::task::spawn_blocking(move || list_files(&root, &walk)).await??
+    };
+
+    let root_source = root.to_str().context("path is not valid utf-8")?;
+    let prefix = format!("{root_source}{MAIN_SEPARATOR}");
+    let stored = vdb
+        .get_contents_under(&prefix)
+        .await?
+        .into_iter()
+        .filter_map(|content| Some((content.source.clone()?, content)))
+        .collect::<HashMap<String, Content>>();
+
+    let mut reports = Vec::new();
+    // entries the walk could not read, reported once like failed files
+    for report in listing.errors {
+        if failures.insert(report.path.clone(), None) != Some(None) {
+            on_file(&report);
+            reports.push(report);
+        }
+    }
+
+    let mut on_disk = HashSet::new();
+    for path in listing.files {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(reports);
+        }
+        let mtime = tokio::fs::metadata(&path)
+            .await
+            .ok()
+            .and_then(|metadata| mtime_ms(&metadata));
+        if failures.get(&path) == Some(&mtime) {
+            continue;
+        }
+
+        let outcome = match source_of(&path).await {
+            Ok(source) => {
+                let unchanged =
+                    mtime.is_some() && stored.get(&source).and_then(|c| c.mtime_ms) == mtime;
+                on_disk.insert(source);
+                if unchanged {
+                    continue;
+                }
+                ingest_file(vdb, &path, chunking).await
+            }
+            Err(e) => FileOutcome::Failed(e),
+        };
+        let outcome = match outcome {
+            FileOutcome::Ingested(ingested) => {
+                match vdb.set_watch_root(&ingested.content.id, root_source).await {
+                    Ok(()) => FileOutcome::Ingested(ingested),
+                    Err(e) => FileOutcome::Failed(e),
+                }
+            }
+            // unsupported files would be reported again on every sync
+            FileOutcome::Skipped(_) => continue,
+            outcome => outcome,
+        };
+        if matches!(outcome, FileOutcome::Failed(_)) {
+            failures.insert(path.clone(), mtime);
+        } else {
+            failures.remove(&path);
+        }
+        let report = FileReport { path, outcome };
+        on_file(&report);
+        reports.push(report);
+    }
+
+    let globs = WalkGlobs::new(root, walk)?;
+    for (source, content) in stored {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        if on_disk.contains(&source) || content.watch_root.as_deref() != Some(root_source) {
+            continue;
+        }
+        // a file the globs now leave out is no longer ours to remove
+        let path = PathBuf::from(source);
+        if !globs.allows(&path) {
+            continue;
+        }
+        // a file the walk did not list may still be there, e.g. unreadable for a moment
+        match tokio::fs::metadata(&path).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            _ => continue,
+        }
+
+        let outcome = match vdb.delete_content(&content.id.id.to_raw()).await {
+            Ok(()) => FileOutcome::Removed(content.id),
+            Err(e) => FileOutcome::Failed(e),
+        };
+        let report = FileReport { path, outcome };
+        on_file(&report);
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+// sources are canonical paths, a symlinked file is stored under its target
+async fn source_of(path: &Path) -> anyhow::Result<String> {
+    let source = tokio::fs::canonicalize(path)
+        .await
+        .with_context(|| format!("unable to resolve {}", path.display()))?;
+    source
+        .into_os_string()
+        .into_string()
+        .ok()
+        .context("path is not valid utf-8")
+}
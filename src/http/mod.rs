@@ -1,26 +1,526 @@
-// use axum::{
-//     http::StatusCode,
-//     routing::{get, post},
-//     Json, Router,
-// };
-
-// use crate::qa::answer_query;
-
-// pub fn router() -> Router {
-//     Router::new()
-//         .route("/api", get(|| async { "hello" }))
-//         .route("/api/ask", post(ask_question))
-// }
-
-// async fn ask_question(Json(payload): Json<String>) -> (StatusCode, Json<String>) {
-//     match answer_query(&payload).await {
-//         Ok(answer) => (StatusCode::OK, Json(answer)),
-//         Err(err) => {
-//             eprintln!("error answering question: {}", err);
-//             (
-//                 StatusCode::INTERNAL_SERVER_ERROR,
-//                 Json("failed to answer question".to_string()),
-//             )
-//         }
-//     }
-// }
+use anyhow::Context;
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{net::SocketAddr, sync::Arc};
+use surrealdb::sql::thing;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+use crate::{
+    ai::{cache::CacheStats, worker_pool::StreamEvent, AI},
+    data::{
+        chunker::{ChunkOptions, ChunkStrategy},
+        database::{Content, IngestStatus, Ingested, Turn, VDB},
+        ingest::{ingest_note, parse_file, store_file},
+        retrieval::{RetrievalOptions, SearchMode},
+    },
+    qa::{answer_query, answer_query_stream, ContextReport, Source},
+    router::session::SessionKey,
+};
+
+// the whole conversation is returned, not just what fits in a prompt
+const SESSION_HISTORY_LIMIT: usize = 1000;
+
+// uploads are whole pdfs, the axum default of 2mb is too small
+const UPLOAD_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub vdb: Arc<VDB>,
+    pub ai: Arc<AI>,
+    // server-wide defaults, requests can override individual fields
+    pub retrieval: RetrievalOptions,
+    // default chunking of remembered notes and uploads
+    pub chunking: ChunkOptions,
+}
+
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}"))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Json(ErrorResponse {
+                error: self.message,
+            }),
+        )
+            .into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct AskRequest {
+    pub query: String,
+    // continue an existing conversation, omit to start a new one
+    #[serde(default)]
+    pub session_id: Option<SessionKey>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    // only filters vector hits, `min_score` is the old name
+    #[serde(default, alias = "min_score")]
+    pub min_similarity: Option<f32>,
+    #[serde(default)]
+    pub neighbors_before: Option<u16>,
+    #[serde(default)]
+    pub neighbors_after: Option<u16>,
+    #[serde(default)]
+    pub context_budget: Option<usize>,
+    #[serde(default)]
+    pub search_mode: Option<SearchMode>,
+    #[serde(default)]
+    pub vector_weight: Option<f32>,
+    #[serde(default)]
+    pub keyword_weight: Option<f32>,
+    #[serde(default)]
+    pub sparse_weight: Option<f32>,
+    #[serde(default)]
+    pub rrf_k: Option<f32>,
+}
+
+impl AskRequest {
+    fn retrieval_options(&self, defaults: &RetrievalOptions) -> ApiResult<RetrievalOptions> {
+        let options = RetrievalOptions {
+            top_k: self.top_k.unwrap_or(defaults.top_k),
+            min_similarity: self.min_similarity.unwrap_or(defaults.min_similarity),
+            neighbors_before: self.neighbors_before.unwrap_or(defaults.neighbors_before),
+            neighbors_after: self.neighbors_after.unwrap_or(defaults.neighbors_after),
+            context_budget: self.context_budget.unwrap_or(defaults.context_budget),
+            search_mode: self.search_mode.unwrap_or(defaults.search_mode),
+            vector_weight: self.vector_weight.unwrap_or(defaults.vector_weight),
+            keyword_weight: self.keyword_weight.unwrap_or(defaults.keyword_weight),
+            sparse_weight: self.sparse_weight.unwrap_or(defaults.sparse_weight),
+            rrf_k: self.rrf_k.unwrap_or(defaults.rrf_k),
+        };
+        options
+            .check()
+            .map_err(|e| ApiError::bad_request(e.to_string()))?;
+        Ok(options)
+    }
+}
+
+#[derive(Serialize)]
+pub struct AskResponse {
+    pub answer: String,
+    pub sources: Vec<Source>,
+    pub context: ContextReport,
+    pub session_id: SessionKey,
+    pub standalone_query: String,
+}
+
+#[derive(Serialize)]
+pub struct TurnResponse {
+    pub question: String,
+    pub standalone_query: String,
+    pub answer: String,
+    pub created_at: String,
+}
+
+impl From<Turn> for TurnResponse {
+    fn from(turn: Turn) -> Self {
+        Self {
+            question: turn.question,
+            standalone_query: turn.standalone_query,
+            answer: turn.answer,
+            created_at: turn.created_at.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub session_id: SessionKey,
+    pub turns: Vec<TurnResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct RememberRequest {
+    pub content: String,
+    #[serde(flatten)]
+    pub chunking: ChunkParams,
+}
+
+// per request overrides of how content is chunked, as json fields or upload query params
+#[derive(Deserialize)]
+pub struct ChunkParams {
+    pub chunk_strategy: Option<ChunkStrategy>,
+    pub chunk_size: Option<usize>,
+    pub chunk_overlap: Option<usize>,
+}
+
+impl ChunkParams {
+    fn chunk_options(&self, defaults: &ChunkOptions) -> ApiResult<ChunkOptions> {
+        let options = ChunkOptions {
+            strategy: self.chunk_strategy.unwrap_or(defaults.strategy),
+            max_tokens: self.chunk_size.unwrap_or(defaults.max_tokens),
+            overlap: self.chunk_overlap.unwrap_or(defaults.overlap),
+        };
+        options
+            .check()
+            .map_err(|e| ApiError::bad_request(e.to_string()))?;
+        Ok(options)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    #[serde(default)]
+    pub start: u16,
+    #[serde(default = "default_limit")]
+    pub limit: u16,
+}
+
+fn default_limit() -> u16 {
+    10
+}
+
+#[derive(Serialize)]
+pub struct ContentResponse {
+    pub id: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    // outbound links of an html page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub links: Option<Vec<String>>,
+    pub created_at: String,
+    // set on remember and upload responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<IngestStatus>,
+}
+
+// one file of a multipart upload, `content` when it was ingested and `error` otherwise
+#[derive(Serialize)]
+pub struct UploadResult {
+    pub file_name: String,
+    pub source: String,
+    // 201 when ingested, 422 when the file could not be read, 500 when storing it failed
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<ContentResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ContentResponse {
+    // listings leave out the full text, it can be megabytes for a pdf
+    fn summary(content: Content) -> Self {
+        Self {
+            id: content.id.id.to_raw(),
+            title: content.title,
+            source: content.source,
+            text: None,
+            links: None,
+            created_at: content.created_at.to_string(),
+            status: None,
+        }
+    }
+
+    fn ingested(ingested: Ingested) -> Self {
+        Self {
+            status: Some(ingested.status),
+            ..Self::summary(ingested.content)
+        }
+    }
+
+    fn full(content: Content) -> Self {
+        Self {
+            id: content.id.id.to_raw(),
+            title: content.title,
+            source: content.source,
+            text: Some(content.text),
+            links: content.links,
+            created_at: content.created_at.to_string(),
+            status: None,
+        }
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/api", get(|| async { "hello" }))
+        .route("/api/ask", post(ask_question))
+        .route("/api/ask/stream", post(ask_question_stream))
+        .route("/api/remember", post(remember))
+        .route(
+            "/api/upload",
+            post(upload).layer(DefaultBodyLimit::max(UPLOAD_LIMIT)),
+        )
+        .route("/api/content", get(list_content).delete(forget_all))
+        .route("/api/content/{id}", get(get_content).delete(forget_content))
+        .route(
+            "/api/sessions/{id}",
+            get(get_session).delete(forget_session),
+        )
+        .route("/api/cache", get(cache_stats))
+        .with_state(state)
+}
+
+pub async fn serve(addr: SocketAddr, state: AppState) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("unable to bind {addr}"))?;
+    eprintln!("listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(state))
+        .await
+        .context("http server error")?;
+    Ok(())
+}
+
+async fn ask_question(
+    State(state): State<AppState>,
+    Json(payload): Json<AskRequest>,
+) -> ApiResult<Json<AskResponse>> {
+    if payload.query.trim().is_empty() {
+        return Err(ApiError::bad_request("query is empty"));
+    }
+    let options = payload.retrieval_options(&state.retrieval)?;
+    let session_id = payload.session_id.clone().unwrap_or_default();
+    let answer = answer_query(&payload.query, &state.vdb, &state.ai, &options, &session_id).await?;
+    Ok(Json(AskResponse {
+        answer: answer.text,
+        sources: answer.sources,
+        context: answer.context,
+        session_id: answer.session_id,
+        standalone_query: answer.standalone_query,
+    }))
+}
+
+// server-sent events: one `sources` event, a `token` event per decoded token,
+// then a single `done` (answer and sources) or `error`
+async fn ask_question_stream(
+    State(state): State<AppState>,
+    Json(payload): Json<AskRequest>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, axum::Error>>>> {
+    if payload.query.trim().is_empty() {
+        return Err(ApiError::bad_request("query is empty"));
+    }
+    let options = payload.retrieval_options(&state.retrieval)?;
+    let session_id = payload.session_id.clone().unwrap_or_default();
+    let answer =
+        answer_query_stream(&payload.query, &state.vdb, &state.ai, &options, &session_id).await?;
+    let sources = answer.sources;
+    let context = answer.context;
+    let session_id = answer.session_id;
+    let standalone_query = answer.standalone_query;
+    let head = tokio_stream::once(Event::default().event("sources").json_data(&sources));
+    let tokens = ReceiverStream::new(answer.events).map(move |event| match event {
+        StreamEvent::Token(token) => Event::default()
+            .event("token")
+            .json_data(json!({ "token": token })),
+        StreamEvent::Done(result) => Event::default().event("done").json_data(AskResponse {
+            answer: result.0,
+            sources: sources.clone(),
+            context: context.clone(),
+            session_id: session_id.clone(),
+            standalone_query: standalone_query.clone(),
+        }),
+        StreamEvent::Error(error) => Event::default()
+            .event("error")
+            .json_data(ErrorResponse { error }),
+    });
+    Ok(Sse::new(head.chain(tokens)).keep_alive(KeepAlive::default()))
+}
+
+async fn remember(
+    State(state): State<AppState>,
+    Json(payload): Json<RememberRequest>,
+) -> ApiResult<(StatusCode, Json<ContentResponse>)> {
+    if payload.content.trim().is_empty() {
+        return Err(ApiError::bad_request("content is empty"));
+    }
+    let chunking = payload.chunking.chunk_options(&state.chunking)?;
+    let ingested = ingest_note(&state.vdb, &payload.content, &chunking).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(ContentResponse::ingested(ingested)),
+    ))
+}
+
+// sources of http uploads live apart from the file paths the cli ingests
+const UPLOAD_SOURCE_PREFIX: &str = "upload:";
+
+// multipart upload, every field carrying a file name is ingested on its own. a `source` text
+// field names the file that follows it, e.g. its path in the client's tree, so files of the
+// same name in different folders do not replace each other. without one the file name is the
+// source. a failing file does not stop the others, every file gets its own result and the
+// response takes the status of the worst one.
+async fn upload(
+    State(state): State<AppState>,
+    Query(params): Query<ChunkParams>,
+    mut multipart: Multipart,
+) -> ApiResult<(StatusCode, Json<Vec<UploadResult>>)> {
+    let chunking = params.chunk_options(&state.chunking)?;
+    let mut uploaded = Vec::new();
+    let mut source = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::bad_request(e.body_text()))?
+    {
+        let Some(file_name) = field.file_name().map(|s| s.to_string()) else {
+            if field.name() == Some("source") {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::bad_request(e.body_text()))?;
+                if text.trim().is_empty() {
+                    return Err(ApiError::bad_request("source is empty"));
+                }
+                source = Some(text.trim().to_string());
+            }
+            continue;
+        };
+        let bytes = field
+            .bytes()
+            .await
+            .map_err(|e| ApiError::bad_request(e.body_text()))?;
+        let source = format!(
+            "{UPLOAD_SOURCE_PREFIX}{}",
+            source.take().unwrap_or_else(|| file_name.clone())
+        );
+        let stored = match parse_file(&file_name, &bytes) {
+            Ok(parsed) => store_file(&state.vdb, &file_name, &source, parsed, &chunking)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e)),
+            Err(e) => Err((StatusCode::UNPROCESSABLE_ENTITY, e)),
+        };
+        uploaded.push(match stored {
+            Ok(ingested) => UploadResult {
+                file_name,
+                source,
+                status: StatusCode::CREATED.as_u16(),
+                content: Some(ContentResponse::ingested(ingested)),
+                error: None,
+            },
+            Err((status, e)) => UploadResult {
+                file_name,
+                source,
+                status: status.as_u16(),
+                content: None,
+                error: Some(format!("{e:#}")),
+            },
+        });
+    }
+    let Some(worst) = uploaded.iter().map(|result| result.status).max() else {
+        return Err(ApiError::bad_request("no file in upload"));
+    };
+    let status = StatusCode::from_u16(worst).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    Ok((status, Json(uploaded)))
+}
+
+async fn list_content(
+    State(state): State<AppState>,
+    Query(params): Query<ListParams>,
+) -> ApiResult<Json<Vec<ContentResponse>>> {
+    let contents = state
+        .vdb
+        .get_all_content(params.start, params.limit)
+        .await?;
+    Ok(Json(
+        contents.into_iter().map(ContentResponse::summary).collect(),
+    ))
+}
+
+// embedding cache hits and misses since the server started
+async fn cache_stats(State(state): State<AppState>) -> Json<CacheStats> {
+    Json(state.vdb.cache_stats())
+}
+
+async fn get_content(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ContentResponse>> {
+    let content = find_content(&state, &id).await?;
+    Ok(Json(ContentResponse::full(content)))
+}
+
+async fn forget_content(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let content = find_content(&state, &id).await?;
+    state.vdb.delete_content(&content.id.id.to_raw()).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn forget_all(State(state): State<AppState>) -> ApiResult<StatusCode> {
+    state.vdb.delete_all_content().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_session(
+    State(state): State<AppState>,
+    Path(id): Path<SessionKey>,
+) -> ApiResult<Json<SessionResponse>> {
+    let turns = state.vdb.get_turns(&id, SESSION_HISTORY_LIMIT).await?;
+    if turns.is_empty() {
+        return Err(ApiError::not_found(format!("no session with id {id}")));
+    }
+    Ok(Json(SessionResponse {
+        session_id: id,
+        turns: turns.into_iter().map(TurnResponse::from).collect(),
+    }))
+}
+
+async fn forget_session(
+    State(state): State<AppState>,
+    Path(id): Path<SessionKey>,
+) -> ApiResult<StatusCode> {
+    state.vdb.delete_session(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find_content(state: &AppState, id: &str) -> ApiResult<Content> {
+    let id = id.strip_prefix("content:").unwrap_or(id);
+    let content_id = thing(&format!("content:{}", id))
+        .map_err(|_| ApiError::bad_request(format!("invalid content id {id}")))?;
+    // a failing database is a server error, not a missing id
+    match state.vdb.find_content(content_id).await? {
+        Some(content) => Ok(content),
+        None => Err(ApiError::not_found(format!("no content with id {id}"))),
+    }
+}
@@ -1,8 +1,8 @@
 pub mod ai;
+pub mod app;
 pub mod cli;
 pub mod data;
 pub mod http;
 pub mod qa;
+pub mod router;
 pub mod utils;
-pub mod app;
-
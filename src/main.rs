@@ -1,25 +1,101 @@
+use clap::Parser;
 use lib::{
-    ai::{embedding::Embedder, worker_pool::WorkerPool, AI},
-    cli,
-    data::database::VDB,
+    ai::{
+        embedding::{Embedder, EmbedderOptions},
+        registry::DEFAULT_MODEL,
+        sparse::SparseEmbedder,
+        worker_pool::WorkerPool,
+        SparseEmbeddingEngine, AI,
+    },
+    cli::{self, Cli, Commands},
+    data::{
+        chunker::ChunkOptions,
+        database::{self, VDB},
+        retrieval::RetrievalOptions,
+    },
+    http,
     utils::device,
 };
-use std::{error::Error, sync::Arc};
+use std::{error::Error, process::ExitCode, sync::Arc};
 use tokio::sync::Mutex;
 
+// the splade model for sparse vectors, independent of the dense embedding model
+const SPARSE_MODEL: &str = "ibm-granite/granite-embedding-30m-sparse";
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let cli = Cli::parse();
+    let retrieval = RetrievalOptions::from(&cli.retrieval);
+    retrieval.check()?;
+    let chunking = ChunkOptions::from(&cli.chunking);
+
     let device = Arc::new(device(false)?);
+    let embedder_options = EmbedderOptions::from(&cli.embedding);
+    let db = database::connect().await?;
+    // queries must use the model the stored vectors were made with
+    let embedding_model = match &cli.embedding.embedding_model {
+        Some(model) => model.clone(),
+        None => database::stored_embedding_model(&db)
+            .await?
+            .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+    };
     let embedding_serivce =
-        Arc::new(Embedder::new("ibm-granite/granite-embedding-30m-sparse").await?);
-    let inference_pool = Arc::new(Mutex::new(
-        WorkerPool::new(1, 5, device.clone(), "Demonthos/dolphin-2_6-phi-2-candle").await?,
-    ));
-    let ai_service = Arc::new(AI::new(embedding_serivce.clone(), inference_pool));
-
-    let vdb = Arc::new(VDB::new(embedding_serivce.clone()).await?);
+        Arc::new(Embedder::new(&embedding_model, embedder_options.clone()).await?);
+    // granite sparse is a splade model, its masked lm head gives the term-weight vectors
+    let sparse_service: Option<Arc<dyn SparseEmbeddingEngine + Send + Sync>> =
+        if cli.embedding.no_sparse || !cli.command.needs_sparse() {
+            None
+        } else {
+            Some(Arc::new(
+                SparseEmbedder::new(SPARSE_MODEL, embedder_options).await?,
+            ))
+        };
+    if cli.embedding.no_sparse && cli.command.stores_chunks() {
+        eprintln!(
+            "warning: --no-sparse stores chunks without sparse postings, sparse search will not \
+             find them until `reembed` runs without --no-sparse"
+        );
+    }
+    // reembed is how a database moves to another model, so it must open with a mismatch
+    let vdb = if matches!(cli.command, Commands::Reembed) {
+        VDB::for_reembed(db, embedding_serivce.clone(), sparse_service).await?
+    } else {
+        VDB::new(db, embedding_serivce.clone(), sparse_service).await?
+    };
+    let vdb = Arc::new(vdb);
 
-    cli::runner::run_repl(vdb.clone(), ai_service.clone()).await?;
+    // skip loading the generation model for commands that never use it
+    let ai_service = if cli.command.needs_inference() {
+        let pool =
+            WorkerPool::new(1, 5, device.clone(), "Demonthos/dolphin-2_6-phi-2-candle").await?;
+        let tokenizer = pool.tokenizer();
+        let inference_pool = Arc::new(Mutex::new(pool));
+        Some(Arc::new(AI::new(
+            embedding_serivce.clone(),
+            inference_pool,
+            tokenizer,
+        )))
+    } else {
+        None
+    };
 
-    Ok(())
+    match (cli.command, ai_service) {
+        (Commands::Tui, Some(ai_service)) => {
+            cli::runner::run_repl(vdb.clone(), ai_service.clone(), retrieval, chunking).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+        (Commands::Serve { addr }, Some(ai_service)) => {
+            let state = http::AppState {
+                vdb: vdb.clone(),
+                ai: ai_service.clone(),
+                retrieval,
+                chunking,
+            };
+            http::serve(addr, state).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+        (command, ai_service) => {
+            Ok(cli::commands::run_command(command, vdb, ai_service, retrieval, chunking).await)
+        }
+    }
 }
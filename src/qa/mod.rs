@@ -1,41 +1,677 @@
-use std::sync::Arc;
+use std::{cmp::Ordering, collections::HashMap, fmt, sync::Arc};
 
 use anyhow::Error;
+use serde::Serialize;
+use surrealdb::sql::Thing;
+use tokio::sync::mpsc;
 
 use crate::{
-    ai::{worker_pool::InferenceResult, AI},
-    data::database::{VectorIndex, VDB},
+    ai::{
+        worker_pool::{StreamEvent, STREAM_BUFFER},
+        AI, SAMPLE_LEN,
+    },
+    data::{
+        database::{Turn, VectorIndex, VDB},
+        markdown::BREADCRUMB_SEPARATOR,
+        retrieval::RetrievalOptions,
+    },
+    router::session::{SessionKey, HISTORY_TURNS},
 };
 
-// pub struct
+const SNIPPET_LEN: usize = 160;
+
+// a retrieved chunk the answer can cite as `[index]`
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    pub index: usize,
+    pub content_id: String,
+    pub title: String,
+    // best matching chunk, and the range of chunks the passage spans around it
+    pub chunk_number: u32,
+    pub first_chunk: u32,
+    pub last_chunk: u32,
+    // reciprocal rank fusion score of the best chunk, passages are ordered by it
+    pub score: f32,
+    // what each retrieval method scored the best chunk before fusion
+    pub scores: MethodScores,
+    pub snippet: String,
+    // headings of the markdown section the best chunk sits in, "Guide > Install > Linux"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breadcrumb: Option<String>,
+}
+
+impl Source {
+    // title plus the section inside it when the document had headings
+    pub fn label(&self) -> String {
+        match &self.breadcrumb {
+            Some(breadcrumb) => format!("{}{}{}", self.title, BREADCRUMB_SEPARATOR, breadcrumb),
+            None => self.title.clone(),
+        }
+    }
+}
+
+// what each retrieval method scored a chunk. methods that did not find it are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MethodScores {
+    // cosine similarity to the query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector: Option<f32>,
+    // bm25
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<f32>,
+    // splade dot product
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sparse: Option<f32>,
+}
+
+impl MethodScores {
+    fn set(&mut self, method: Method, score: Option<f32>) {
+        match method {
+            Method::Vector => self.vector = score,
+            Method::Keyword => self.keyword = score,
+            Method::Sparse => self.sparse = score,
+        }
+    }
+}
+
+impl fmt::Display for MethodScores {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scores = [
+            ("cosine", self.vector),
+            ("bm25", self.keyword),
+            ("sparse", self.sparse),
+        ];
+        let scores = scores
+            .iter()
+            .filter_map(|(name, score)| Some(format!("{name} {:.3}", (*score)?)))
+            .collect::<Vec<String>>();
+        write!(f, "{}", scores.join(", "))
+    }
+}
+
+// the rankings that are fused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Vector,
+    Keyword,
+    Sparse,
+}
+
+// a chunk found by one or more rankings
+#[derive(Debug, Clone)]
+struct Hit {
+    chunk: VectorIndex,
+    // reciprocal rank fusion score, hits are ordered by it
+    score: f32,
+    scores: MethodScores,
+}
+
+// one numbered passage of the prompt: a span of one document covering one or more matched
+// chunks and their neighbours
+#[derive(Debug, Clone)]
+pub struct Passage {
+    pub source: Source,
+    pub text: String,
+}
+
+// what had to give way for the passages to fit the token budget
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ContextReport {
+    // tokens left for the prompt once the answer's `SAMPLE_LEN` is reserved
+    pub budget: usize,
+    pub prompt_tokens: usize,
+    // passages left out entirely, lowest scoring first to go
+    pub dropped: Vec<Source>,
+    // earlier turns of the session left out of the prompt, oldest first to go
+    pub dropped_turns: usize,
+    // citation index of a passage whose text was cut to fit
+    pub shortened: Option<usize>,
+}
+
+impl ContextReport {
+    pub fn is_truncated(&self) -> bool {
+        !self.dropped.is_empty() || self.dropped_turns > 0 || self.shortened.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    pub text: String,
+    pub sources: Vec<Source>,
+    pub context: ContextReport,
+    pub session_id: SessionKey,
+    // what retrieval searched for after the question was rewritten against the history
+    pub standalone_query: String,
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// the sources are known before generation starts, the text arrives on `events`
+pub struct AnswerStream {
+    pub sources: Vec<Source>,
+    pub context: ContextReport,
+    pub session_id: SessionKey,
+    pub standalone_query: String,
+    pub events: mpsc::Receiver<StreamEvent>,
+}
 
 pub async fn answer_query(
     query: &str,
     vdb: &Arc<VDB>,
     ai: &Arc<AI>,
-) -> Result<InferenceResult, Error> {
-    let context = build_context_for_query(&ai, &vdb, query).await?;
-    let answer = ai.answer_question_with_context(query, &context).await?;
-    Ok(answer)
+    options: &RetrievalOptions,
+    session_id: &SessionKey,
+) -> Result<Answer, Error> {
+    let history = vdb.get_turns(session_id, HISTORY_TURNS).await?;
+    let standalone_query = ai
+        .rewrite_query(query, &history, options.context_budget, session_id)
+        .await?;
+    let context = build_context_for_query(&vdb, &standalone_query, options).await?;
+    let (context, history, report) = fit_to_budget(ai, query, context, history, options)?;
+    let answer = ai
+        .answer_question_with_context(query, &context, &history, session_id)
+        .await?;
+    vdb.insert_turn(session_id, query, &standalone_query, &answer.0)
+        .await?;
+    Ok(Answer {
+        text: answer.0,
+        sources: context.into_iter().map(|p| p.source).collect(),
+        context: report,
+        session_id: session_id.clone(),
+        standalone_query,
+    })
 }
 
-pub async fn build_context_for_query(
+// same retrieval as `answer_query`, but the answer is streamed token by token.
+// the turn is saved to the session once the worker reports `Done`.
+pub async fn answer_query_stream(
+    query: &str,
+    vdb: &Arc<VDB>,
     ai: &Arc<AI>,
+    options: &RetrievalOptions,
+    session_id: &SessionKey,
+) -> Result<AnswerStream, Error> {
+    let history = vdb.get_turns(session_id, HISTORY_TURNS).await?;
+    let standalone_query = ai
+        .rewrite_query(query, &history, options.context_budget, session_id)
+        .await?;
+    let context = build_context_for_query(&vdb, &standalone_query, options).await?;
+    let (context, history, report) = fit_to_budget(ai, query, context, history, options)?;
+    let mut worker_events = ai
+        .answer_question_with_context_stream(query, &context, &history, session_id)
+        .await?;
+
+    let (tx, events) = mpsc::channel(STREAM_BUFFER);
+    {
+        let vdb = vdb.clone();
+        let session_id = session_id.clone();
+        let question = query.to_string();
+        let standalone_query = standalone_query.clone();
+        tokio::spawn(async move {
+            while let Some(event) = worker_events.recv().await {
+                if let StreamEvent::Done(result) = &event {
+                    let saved = vdb
+                        .insert_turn(&session_id, &question, &standalone_query, &result.0)
+                        .await;
+                    if let Err(e) = saved {
+                        let _ = tx
+                            .send(StreamEvent::Error(format!("unable to save turn: {e}")))
+                            .await;
+                        break;
+                    }
+                }
+                // the listener went away, dropping `worker_events` stops the generation
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    Ok(AnswerStream {
+        sources: context.into_iter().map(|p| p.source).collect(),
+        context: report,
+        session_id: session_id.clone(),
+        standalone_query,
+        events,
+    })
+}
+
+pub async fn build_context_for_query(
     vdb: &Arc<VDB>,
     query: &str,
-) -> Result<Vec<VectorIndex>, Error> {
-    let query_embedding: Vec<f32> = ai
-        .embedder
-        .get_embeddings(query)?
-        .reshape((384,))?
-        .to_vec1()?;
-    let related_content = vdb.get_related_chunks(query_embedding).await?;
-    let mut context: Vec<VectorIndex> = vec![];
-    for related in related_content.iter() {
-        let content = vdb
-            .get_adjacent_chunks(related.content_id.clone(), 1, 1, related.chunk_number)
+    options: &RetrievalOptions,
+) -> Result<Vec<Passage>, Error> {
+    options.check()?;
+    let query_embedding = vdb.embed_query(query).await?;
+    let mut rankings = Vec::new();
+    if options.vector_weight > 0.0 {
+        let hits = vdb.get_related_chunks(query_embedding, options).await?;
+        rankings.push((Method::Vector, hits, options.vector_weight));
+    }
+    if options.keyword_weight > 0.0 {
+        let hits = vdb.get_keyword_chunks(query, options).await?;
+        rankings.push((Method::Keyword, hits, options.keyword_weight));
+    }
+    if options.sparse_weight > 0.0 {
+        let hits = vdb.get_sparse_chunks(query, options).await?;
+        rankings.push((Method::Sparse, hits, options.sparse_weight));
+    }
+    let related_content = reciprocal_rank_fusion(rankings, options.rrf_k, options.top_k);
+
+    let mut titles: HashMap<String, String> = HashMap::new();
+    let mut context: Vec<Passage> = vec![];
+    for span in merge_into_spans(related_content, options) {
+        let chunks = vdb
+            .get_chunk_range(span.content_id.clone(), span.start, span.end)
             .await?;
-        context.extend(content);
+
+        let key = span.content_id.to_string();
+        if !titles.contains_key(&key) {
+            let title = vdb
+                .get_content(span.content_id.clone())
+                .await
+                .map(|c| c.title)
+                .unwrap_or_else(|_| key.clone());
+            titles.insert(key.clone(), title);
+        }
+
+        // the best scoring hit of the span stands in for it in the citation
+        let best = &span.hits[0];
+        context.push(Passage {
+            source: Source {
+                index: 0,
+                content_id: span.content_id.id.to_raw(),
+                title: titles[&key].clone(),
+                chunk_number: best.chunk.chunk_number,
+                first_chunk: chunks.first().map_or(span.start, |c| c.chunk_number),
+                last_chunk: chunks.last().map_or(span.end, |c| c.chunk_number),
+                score: best.score,
+                scores: best.scores,
+                snippet: snippet(&best.chunk.content_chunk),
+                breadcrumb: best
+                    .chunk
+                    .metadata
+                    .get("breadcrumb")
+                    .and_then(|b| b.as_str())
+                    .map(str::to_string),
+            },
+            text: join_chunks(&chunks),
+        });
+    }
+
+    context.sort_by(|a, b| {
+        b.source
+            .score
+            .partial_cmp(&a.source.score)
+            .unwrap_or(Ordering::Equal)
+    });
+    for (i, passage) in context.iter_mut().enumerate() {
+        passage.source.index = i + 1;
     }
     Ok(context)
 }
+
+// keeps the prompt inside `context_budget` minus the tokens reserved for the answer.
+// passages arrive sorted best first, so they are dropped from the back, then the oldest
+// turns of the conversation go. if the best passage alone is still too long its text is
+// cut instead of answering without context.
+pub fn fit_to_budget(
+    ai: &AI,
+    query: &str,
+    mut passages: Vec<Passage>,
+    mut history: Vec<Turn>,
+    options: &RetrievalOptions,
+) -> Result<(Vec<Passage>, Vec<Turn>, ContextReport), Error> {
+    let mut report = ContextReport {
+        budget: options.context_budget.saturating_sub(SAMPLE_LEN),
+        ..Default::default()
+    };
+
+    let mut tokens = ai.prompt_tokens(query, &passages, &history)?;
+    while tokens > report.budget && passages.len() > 1 {
+        if let Some(passage) = passages.pop() {
+            report.dropped.push(passage.source);
+        }
+        tokens = ai.prompt_tokens(query, &passages, &history)?;
+    }
+
+    while tokens > report.budget && !history.is_empty() {
+        history.remove(0);
+        report.dropped_turns += 1;
+        tokens = ai.prompt_tokens(query, &passages, &history)?;
+    }
+
+    if tokens > report.budget {
+        if let Some(passage) = passages.first_mut() {
+            let overflow = tokens - report.budget;
+            let keep = ai.count_tokens(&passage.text)?.saturating_sub(overflow);
+            passage.text = ai.truncate_to_tokens(&passage.text, keep)?;
+            report.shortened = Some(passage.source.index);
+            tokens = ai.prompt_tokens(query, &passages, &history)?;
+        }
+    }
+
+    report.prompt_tokens = tokens;
+    Ok((passages, history, report))
+}
+
+// a contiguous run of chunks of one document, covering one or more hits and their neighbours
+struct Span {
+    content_id: Thing,
+    start: u32,
+    end: u32,
+    // sorted by score, best first
+    hits: Vec<Hit>,
+}
+
+// widens every hit by its neighbour window and merges windows that overlap or touch within
+// the same document, so each chunk reaches the prompt at most once and in document order.
+fn merge_into_spans(hits: Vec<Hit>, options: &RetrievalOptions) -> Vec<Span> {
+    let mut by_content: Vec<(Thing, Vec<Hit>)> = Vec::new();
+    for hit in hits {
+        match by_content
+            .iter_mut()
+            .find(|(id, _)| *id == hit.chunk.content_id)
+        {
+            Some((_, group)) => group.push(hit),
+            None => by_content.push((hit.chunk.content_id.clone(), vec![hit])),
+        }
+    }
+
+    let mut spans: Vec<Span> = Vec::new();
+    for (content_id, mut group) in by_content {
+        group.sort_by_key(|hit| hit.chunk.chunk_number);
+        let mut current: Option<Span> = None;
+        for hit in group {
+            let number = hit.chunk.chunk_number;
+            let start = number.saturating_sub(u32::from(options.neighbors_before));
+            let end = number.saturating_add(u32::from(options.neighbors_after));
+            match current.as_mut() {
+                Some(span) if start <= span.end.saturating_add(1) => {
+                    span.end = span.end.max(end);
+                    span.hits.push(hit);
+                }
+                _ => {
+                    spans.extend(current.take());
+                    current = Some(Span {
+                        content_id: content_id.clone(),
+                        start,
+                        end,
+                        hits: vec![hit],
+                    });
+                }
+            }
+        }
+        spans.extend(current);
+    }
+
+    for span in spans.iter_mut() {
+        span.hits
+            .sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    }
+    spans
+}
+
+// merges several rankings of the same chunks. each ranking adds `weight / (k + rank)` to
+// the chunks it contains, so a chunk found by both keyword and vector search rises to the
+// top without having to compare bm25 scores against cosine similarities. the score each
+// ranking gave a chunk is kept next to the fused one. equal scores keep the order in which
+// the chunks were first found, the rankings taken in order.
+fn reciprocal_rank_fusion(
+    rankings: Vec<(Method, Vec<VectorIndex>, f32)>,
+    k: f32,
+    limit: usize,
+) -> Vec<Hit> {
+    let mut fused: Vec<Hit> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (method, chunks, weight) in rankings {
+        for (rank, chunk) in chunks.into_iter().enumerate() {
+            let score = chunk.score;
+            let position = *positions.entry(chunk.id.to_string()).or_insert_with(|| {
+                fused.push(Hit {
+                    chunk,
+                    score: 0.0,
+                    scores: MethodScores::default(),
+                });
+                fused.len() - 1
+            });
+            let hit = &mut fused[position];
+            hit.score += weight / (k + rank as f32 + 1.0);
+            hit.scores.set(method, score);
+        }
+    }
+
+    // a stable sort, so ties stay in the order they were found
+    fused.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    fused.truncate(limit);
+    fused
+}
+
+fn join_chunks(chunks: &[VectorIndex]) -> String {
+    chunks
+        .iter()
+        .map(|c| c.content_chunk.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn snippet(text: &str) -> String {
+    match text.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surrealdb::Datetime;
+
+    fn chunk(content: &str, number: u32, score: f32) -> VectorIndex {
+        let id = format!("{content}_{number}");
+        VectorIndex {
+            id: Thing::from(("vector_index", id.as_str())),
+            content_id: Thing::from(("content", content)),
+            content_chunk: format!("chunk {number} of {content}"),
+            chunk_number: number,
+            vector: Vec::new(),
+            metadata: serde_json::Value::Null,
+            created_at: Datetime::default(),
+            score: Some(score),
+        }
+    }
+
+    fn hit(content: &str, number: u32, score: f32) -> Hit {
+        Hit {
+            chunk: chunk(content, number, score),
+            score,
+            scores: MethodScores::default(),
+        }
+    }
+
+    fn window(before: u16, after: u16) -> RetrievalOptions {
+        RetrievalOptions {
+            neighbors_before: before,
+            neighbors_after: after,
+            ..Default::default()
+        }
+    }
+
+    // (content, start, end, chunk numbers of the hits best first) of every span
+    fn layout(spans: &[Span]) -> Vec<(String, u32, u32, Vec<u32>)> {
+        spans
+            .iter()
+            .map(|span| {
+                let hits = span.hits.iter().map(|h| h.chunk.chunk_number).collect();
+                (span.content_id.id.to_raw(), span.start, span.end, hits)
+            })
+            .collect()
+    }
+
+    fn ids(hits: &[Hit]) -> Vec<String> {
+        hits.iter().map(|hit| hit.chunk.id.id.to_raw()).collect()
+    }
+
+    #[test]
+    fn fusion_ranks_chunks_found_by_several_methods_first() {
+        let vector = vec![chunk("a", 0, 0.9), chunk("a", 1, 0.8)];
+        let keyword = vec![chunk("a", 1, 7.5), chunk("b", 0, 3.0)];
+        let fused = reciprocal_rank_fusion(
+            vec![
+                (Method::Vector, vector, 1.0),
+                (Method::Keyword, keyword, 1.0),
+            ],
+            60.0,
+            10,
+        );
+
+        assert_eq!(ids(&fused), ["a_1", "a_0", "b_0"]);
+        let expected = 1.0 / 62.0 + 1.0 / 61.0;
+        assert!((fused[0].score - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fusion_keeps_the_score_of_every_method() {
+        let vector = vec![chunk("a", 0, 0.9)];
+        let keyword = vec![chunk("a", 0, 7.5)];
+        let fused = reciprocal_rank_fusion(
+            vec![
+                (Method::Vector, vector, 1.0),
+                (Method::Keyword, keyword, 1.0),
+            ],
+            60.0,
+            10,
+        );
+
+        assert_eq!(
+            fused[0].scores,
+            MethodScores {
+                vector: Some(0.9),
+                keyword: Some(7.5),
+                sparse: None,
+            }
+        );
+        // the fused score is not the cosine similarity
+        assert!((fused[0].score - 2.0 / 61.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fusion_weights_scale_a_ranking() {
+        let vector = vec![chunk("a", 0, 0.9)];
+        let keyword = vec![chunk("b", 0, 7.5)];
+        let fused = reciprocal_rank_fusion(
+            vec![
+                (Method::Vector, vector, 1.0),
+                (Method::Keyword, keyword, 2.0),
+            ],
+            60.0,
+            10,
+        );
+
+        assert_eq!(ids(&fused), ["b_0", "a_0"]);
+    }
+
+    #[test]
+    fn fusion_ties_keep_the_order_they_were_found_in() {
+        let vector = vec![chunk("a", 0, 0.9), chunk("a", 1, 0.8)];
+        let keyword = vec![chunk("b", 0, 7.5), chunk("b", 1, 3.0)];
+        let sparse = vec![chunk("c", 0, 12.0)];
+        let fused = reciprocal_rank_fusion(
+            vec![
+                (Method::Vector, vector, 1.0),
+                (Method::Keyword, keyword, 1.0),
+                (Method::Sparse, sparse, 1.0),
+            ],
+            60.0,
+            10,
+        );
+
+        assert_eq!(ids(&fused), ["a_0", "b_0", "c_0", "a_1", "b_1"]);
+    }
+
+    #[test]
+    fn fusion_is_cut_to_the_limit() {
+        let vector = (0..5).map(|n| chunk("a", n, 0.5)).collect();
+        let fused = reciprocal_rank_fusion(vec![(Method::Vector, vector, 1.0)], 60.0, 2);
+
+        assert_eq!(ids(&fused), ["a_0", "a_1"]);
+    }
+
+    #[test]
+    fn overlapping_windows_become_one_span() {
+        let hits = vec![hit("a", 4, 0.5), hit("a", 2, 0.9)];
+        let spans = merge_into_spans(hits, &window(1, 1));
+
+        assert_eq!(layout(&spans), [("a".to_string(), 1, 5, vec![2, 4])]);
+    }
+
+    #[test]
+    fn touching_windows_become_one_span() {
+        let hits = vec![hit("a", 2, 0.9), hit("a", 5, 0.5)];
+        let spans = merge_into_spans(hits, &window(1, 1));
+
+        assert_eq!(layout(&spans), [("a".to_string(), 1, 6, vec![2, 5])]);
+    }
+
+    #[test]
+    fn windows_with_a_gap_stay_apart() {
+        let hits = vec![hit("a", 2, 0.9), hit("a", 6, 0.5)];
+        let spans = merge_into_spans(hits, &window(1, 1));
+
+        assert_eq!(
+            layout(&spans),
+            [
+                ("a".to_string(), 1, 3, vec![2]),
+                ("a".to_string(), 5, 7, vec![6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_never_cross_contents() {
+        let hits = vec![hit("a", 3, 0.9), hit("b", 3, 0.8), hit("a", 4, 0.7)];
+        let spans = merge_into_spans(hits, &window(1, 1));
+
+        assert_eq!(
+            layout(&spans),
+            [
+                ("a".to_string(), 2, 5, vec![3, 4]),
+                ("b".to_string(), 2, 4, vec![3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_follow_document_order_and_hits_score_order() {
+        // contents in the order their first hit was ranked, spans of one content in document
+        // order, the hits of a span best first
+        let hits = vec![
+            hit("b", 9, 0.9),
+            hit("a", 8, 0.8),
+            hit("b", 1, 0.7),
+            hit("b", 10, 0.95),
+        ];
+        let spans = merge_into_spans(hits, &window(0, 0));
+
+        assert_eq!(
+            layout(&spans),
+            [
+                ("b".to_string(), 1, 1, vec![1]),
+                ("b".to_string(), 9, 10, vec![10, 9]),
+                ("a".to_string(), 8, 8, vec![8]),
+            ]
+        );
+    }
+
+    #[test]
+    fn windows_stop_at_the_first_chunk() {
+        let spans = merge_into_spans(vec![hit("a", 0, 0.9)], &window(2, 1));
+
+        assert_eq!(layout(&spans), [("a".to_string(), 0, 1, vec![0])]);
+    }
+}
@@ -1,7 +1,88 @@
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use surrealdb::Uuid;
 
+// how many previous turns are replayed to the model for a follow-up question
+pub const HISTORY_TURNS: usize = 4;
 
-/// Should store
-struct SessionKey {
-    
+/// Identifies one conversation. Its turns are persisted under this key, and the
+/// worker pool uses it to keep a conversation on the same worker.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SessionKey(String);
+
+impl SessionKey {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4().to_string().replace("-", ""))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for SessionKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// an empty key would put everyone who passes one into the same conversation
+impl FromStr for SessionKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s.trim();
+        if key.is_empty() {
+            bail!("session id is empty");
+        }
+        Ok(Self(key.to_string()))
+    }
 }
 
+impl TryFrom<String> for SessionKey {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<SessionKey> for String {
+    fn from(key: SessionKey) -> Self {
+        key.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_keys_are_rejected() {
+        for key in ["", " ", "\t\n"] {
+            assert!(key.parse::<SessionKey>().is_err(), "accepted {key:?}");
+            assert!(serde_json::from_value::<SessionKey>(key.into()).is_err());
+        }
+    }
+
+    #[test]
+    fn keys_are_trimmed() {
+        let key = " abc ".parse::<SessionKey>().map(|key| key.to_string());
+        assert_eq!(key.ok().as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn keys_serialize_as_plain_strings() {
+        let key = SessionKey::new();
+        let json = serde_json::to_value(&key).ok();
+        assert_eq!(json, Some(serde_json::Value::String(key.to_string())));
+    }
+}